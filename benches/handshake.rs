@@ -0,0 +1,28 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::executor::block_on;
+use futures::join;
+
+use shs_async::*;
+use ssb_crypto::{generate_longterm_keypair, NetworkKey};
+
+fn handshake(c: &mut Criterion) {
+    let (s_pk, s_sk) = generate_longterm_keypair();
+    let (c_pk, c_sk) = generate_longterm_keypair();
+    let net_key = NetworkKey::SSB_MAIN_NET;
+
+    c.bench_function("handshake", |b| b.iter(|| {
+        let (mut c2s_w, mut c2s_r) = async_ringbuffer::ring_buffer(1024);
+        let (mut s2c_w, mut s2c_r) = async_ringbuffer::ring_buffer(1024);
+
+        let client_side = client_split(&mut s2c_r, &mut c2s_w,
+                                       net_key.clone(), c_pk, c_sk.clone(), s_pk);
+        let server_side = server_split(&mut c2s_r, &mut s2c_w,
+                                       net_key.clone(), s_pk, s_sk.clone());
+
+        let (c_out, s_out) = block_on(async { join!(client_side, server_side) });
+        (c_out.unwrap(), s_out.unwrap())
+    }));
+}
+
+criterion_group!(benches, handshake);
+criterion_main!(benches);
@@ -1,5 +1,3 @@
-#![feature(async_await, await_macro, futures_api)]
-
 use std::env;
 use std::io::{stdin, stdout, Write};
 use futures::executor::block_on;
@@ -12,10 +10,10 @@ use readwrite::ReadWrite;
 extern crate hex;
 use hex::FromHex;
 
-extern crate ssb_crypto;
-use ssb_crypto::{generate_longterm_keypair, NetworkKey, PublicKey};
-
 // For use with https://github.com/AljoschaMeyer/shs1-testsuite
+//
+// cargo build --example test_client --release
+// node ../shs1-testsuite/test-client.js target/release/examples/test_client
 fn main() -> Result<(), HandshakeError> {
 
     let args: Vec<String> = env::args().collect();
@@ -24,7 +22,11 @@ fn main() -> Result<(), HandshakeError> {
         std::process::exit(1);
     }
 
-    let net_key = NetworkKey::from_slice(&Vec::from_hex(&args[1]).unwrap()).unwrap();
+    let net_key = network_key_from_slice(&Vec::from_hex(&args[1]).unwrap())
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid net_id: {}", e);
+            std::process::exit(1);
+        });
     let server_pk = PublicKey::from_slice(&Vec::from_hex(&args[2]).unwrap()).unwrap();
 
     let (pk, sk) = generate_longterm_keypair();
@@ -38,7 +40,7 @@ fn main() -> Result<(), HandshakeError> {
     v.extend_from_slice(&o.read_noncegen.next()[..]);
     assert_eq!(v.len(), 112);
 
-    stdout().write(&v).unwrap();
+    stdout().write_all(&v).unwrap();
     stdout().flush().unwrap();
 
     Ok(())
@@ -1,12 +1,9 @@
-#![feature(async_await, await_macro)]
-
 use std::env;
 use std::io::{stdin, stdout, Write};
 use futures::executor::block_on;
 use futures::io::AllowStdIo;
 
 use shs_async::*;
-use ssb_crypto::{PublicKey, SecretKey, NetworkKey};
 
 extern crate readwrite;
 use readwrite::ReadWrite;
@@ -26,7 +23,11 @@ fn main() -> Result<(), HandshakeError> {
         std::process::exit(1);
     }
 
-    let net_key = NetworkKey::from_slice(&Vec::from_hex(&args[1]).unwrap()).unwrap();
+    let net_key = network_key_from_slice(&Vec::from_hex(&args[1]).unwrap())
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid net_id: {}", e);
+            std::process::exit(1);
+        });
     let sk = SecretKey::from_slice(&Vec::from_hex(&args[2]).unwrap()).unwrap();
     let pk = PublicKey::from_slice(&Vec::from_hex(&args[3]).unwrap()).unwrap();
 
@@ -39,7 +40,7 @@ fn main() -> Result<(), HandshakeError> {
     v.extend_from_slice(&o.read_noncegen.next()[..]);
     assert_eq!(v.len(), 112);
 
-    stdout().write(&v).unwrap();
+    stdout().write_all(&v).unwrap();
     stdout().flush().unwrap();
 
     Ok(())
@@ -0,0 +1,23 @@
+//! Runs the client side of the handshake against a server that sends a
+//! valid server hello, followed by arbitrary bytes as its server accept.
+
+#![no_main]
+
+use futures::executor::block_on;
+use libfuzzer_sys::fuzz_target;
+use shs_async::{connect, Keypair, NetworkKey};
+
+mod peer;
+use peer::{FuzzPeer, OUR_SEED, PEER_SEED};
+
+fuzz_target!(|data: &[u8]| {
+    let keypair = Keypair::from_seed(&OUR_SEED);
+    let server_pk = Keypair::from_seed(&PEER_SEED).public;
+    let mut input = peer::server_hello();
+    input.extend_from_slice(data);
+    let mut peer = FuzzPeer { input: &input };
+
+    // Any input must produce an error (or, implausibly, an outcome),
+    // never a panic or a hang.
+    let _ = block_on(connect(&mut peer, NetworkKey::SSB_MAIN_NET, &keypair, server_pk));
+});
@@ -0,0 +1,70 @@
+use core::pin::Pin;
+use std::io;
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::task::{Context, Poll};
+use shs_async::state::{ClientHandshake, Handshake, ServerHandshake};
+use shs_async::{EphPublicKey, EphSecretKey, Keypair, NetworkKey};
+
+/// Seed of the fuzzed side's long-term keypair.
+pub const OUR_SEED: [u8; 32] = [1; 32];
+/// Seed of the peer's long-term keypair.
+pub const PEER_SEED: [u8; 32] = [2; 32];
+
+fn eph_keypair(b: u8) -> (EphPublicKey, EphSecretKey) {
+    let sk = EphSecretKey::from_slice(&[b; 32]).unwrap();
+    (sk.public_key(), sk)
+}
+
+/// A valid client hello from the peer, with a fixed ephemeral key, so that
+/// fuzz input after it reaches the client auth parser.
+pub fn client_hello() -> Vec<u8> {
+    let (c, s) = (Keypair::from_seed(&PEER_SEED), Keypair::from_seed(&OUR_SEED));
+    let (eph_pk, eph_sk) = eph_keypair(3);
+    let mut hs = ClientHandshake::with_eph(NetworkKey::SSB_MAIN_NET, c.public, c.secret,
+                                           s.public, eph_pk, eph_sk);
+    hs.next_message().unwrap().to_vec()
+}
+
+/// A valid server hello from the peer, with a fixed ephemeral key, so that
+/// fuzz input after it reaches the server accept parser.
+#[allow(dead_code)] // only used by the client target
+pub fn server_hello() -> Vec<u8> {
+    let s = Keypair::from_seed(&PEER_SEED);
+    let (eph_pk, eph_sk) = eph_keypair(4);
+    let mut hs = ServerHandshake::with_eph(NetworkKey::SSB_MAIN_NET, s.public, s.secret,
+                                           eph_pk, eph_sk);
+    hs.receive(&client_hello()).unwrap();
+    hs.next_message().unwrap().to_vec()
+}
+
+/// A fake peer: reads yield the fuzzer's bytes and then EOF; writes are
+/// accepted and thrown away.
+pub struct FuzzPeer<'a> {
+    pub input: &'a [u8],
+}
+
+impl<'a> AsyncRead for FuzzPeer<'a> {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &mut [u8])
+                 -> Poll<Result<usize, io::Error>> {
+        let n = buf.len().min(self.input.len());
+        buf[..n].copy_from_slice(&self.input[..n]);
+        self.input = &self.input[n..];
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<'a> AsyncWrite for FuzzPeer<'a> {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8])
+                  -> Poll<Result<usize, io::Error>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
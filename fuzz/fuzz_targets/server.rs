@@ -0,0 +1,22 @@
+//! Runs the server side of the handshake against a client that sends a
+//! valid client hello, followed by arbitrary bytes as its client auth.
+
+#![no_main]
+
+use futures::executor::block_on;
+use libfuzzer_sys::fuzz_target;
+use shs_async::{accept, Keypair, NetworkKey};
+
+mod peer;
+use peer::{FuzzPeer, OUR_SEED};
+
+fuzz_target!(|data: &[u8]| {
+    let keypair = Keypair::from_seed(&OUR_SEED);
+    let mut input = peer::client_hello();
+    input.extend_from_slice(data);
+    let mut peer = FuzzPeer { input: &input };
+
+    // Any input must produce an error (or, implausibly, an outcome),
+    // never a panic or a hang.
+    let _ = block_on(accept(&mut peer, NetworkKey::SSB_MAIN_NET, &keypair));
+});
@@ -0,0 +1,122 @@
+//! Blocking versions of [`client`](crate::client) and [`server`](crate::server),
+//! for use with `std::io` streams (eg. `std::net::TcpStream`).
+
+use std::io::{Read, Write};
+
+use ssb_crypto::{NetworkKey, PublicKey, SecretKey};
+use shs_core::messages::*;
+
+use crate::state::{self, ClientHandshake, Handshake, ServerHandshake, Step};
+use crate::{HandshakeError, HandshakeOutcome};
+
+/// Perform the client side of the handshake over a blocking stream.
+/// Pass `&mut stream` if you want to keep using the stream afterwards.
+pub fn client_sync<S>(stream: S,
+                      net_key: NetworkKey,
+                      pk: PublicKey,
+                      sk: SecretKey,
+                      server_pk: PublicKey)
+                      -> Result<HandshakeOutcome, HandshakeError>
+where S: Read + Write
+{
+    let hs = ClientHandshake::new(net_key, pk, sk, server_pk);
+    drive(stream, hs)
+}
+
+/// Perform the server side of the handshake over a blocking stream.
+/// Pass `&mut stream` if you want to keep using the stream afterwards.
+pub fn server_sync<S>(stream: S,
+                      net_key: NetworkKey,
+                      pk: PublicKey,
+                      sk: SecretKey)
+                      -> Result<HandshakeOutcome, HandshakeError>
+where S: Read + Write
+{
+    let hs = ServerHandshake::new(net_key, pk, sk);
+    drive(stream, hs)
+}
+
+fn drive<S, H>(mut stream: S, mut hs: H) -> Result<HandshakeOutcome, HandshakeError>
+where S: Read + Write,
+      H: Handshake,
+{
+    let mut buf = [0u8; ClientAuth::size()]; // largest message
+    let mut outcome = None;
+    loop {
+        if let Some(msg) = hs.next_message() {
+            stream.write_all(msg).map_err(|e| state::fail(&hs, e))?;
+            stream.flush().map_err(|e| state::fail(&hs, e))?;
+            hs.message_sent();
+        }
+        if let Some(o) = outcome {
+            return Ok(o);
+        }
+
+        let buf = &mut buf[..hs.expected_len()];
+        stream.read_exact(buf)
+            .map_err(|e| state::fail(&hs, HandshakeError::from_read(e, hs.expected_step())))?;
+        if let Step::Done(o) = hs.receive(buf)? {
+            outcome = Some(o);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[test]
+    fn tcp_handshake() {
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let s_net_key = net_key.clone();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            server_sync(&mut stream, s_net_key, s_pk, s_sk)
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut c_out = client_sync(&mut stream, net_key, c_pk, c_sk, s_pk).unwrap();
+        let mut s_out = server.join().unwrap().unwrap();
+
+        assert_eq!(c_out.peer_pk, s_pk);
+        assert_eq!(s_out.peer_pk, c_pk);
+        assert_eq!(c_out.write_key, s_out.read_key);
+        assert_eq!(c_out.read_key, s_out.write_key);
+        assert_eq!(c_out.write_noncegen.next(), s_out.read_noncegen.next());
+        assert_eq!(c_out.read_noncegen.next(), s_out.write_noncegen.next());
+    }
+
+    #[test]
+    fn tcp_wrong_server_pk() {
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let (wrong_pk, _) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let s_net_key = net_key.clone();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            server_sync(stream, s_net_key, s_pk, s_sk)
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let r = client_sync(stream, net_key, c_pk, c_sk, wrong_pk);
+        assert!(r.is_err());
+        match server.join().unwrap() {
+            Err(HandshakeError::ClientAuthRejected) => {},
+            _ => panic!(),
+        }
+    }
+}
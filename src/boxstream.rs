@@ -0,0 +1,782 @@
+//! Box stream: the encrypted framing used for application data once the
+//! handshake is done.
+//!
+//! Each frame consists of a 34-byte encrypted header, followed by the
+//! encrypted body (at most 4096 bytes). The header holds the body length and
+//! the body's authentication tag. Consecutive nonces from the handshake's
+//! nonce generators are used for the header and the body.
+//!
+//! A clean shutdown is signalled by a "goodbye" frame: a header of all zeros,
+//! encrypted like any other header. Closing a `BoxStream` sends a goodbye, and
+//! reading one is reported as end-of-stream. If the underlying stream ends
+//! without a goodbye, reads fail with `UnexpectedEof`.
+//!
+//! Nonces are never reused: once either direction's [`NonceGen`] is
+//! exhausted, reads (or writes) in that direction fail, until the writer
+//! rekeys (see below). The writer keeps the last nonce in reserve, so that
+//! the stream can always be closed cleanly.
+//!
+//! # Rekeying
+//!
+//! As an extension to the protocol, [`BoxStream::rekey`] replaces the key and
+//! nonces of the write direction. It sends a rekey frame (a header with a
+//! body length of `0xffff` and an all-zero tag, which the standard protocol
+//! rejects as too long) under the old key, and then switches both ends of the
+//! direction to a key and starting nonce derived from the old key:
+//! `SHA-512("shs_async rekey" || old key)`, split into a 32-byte key and a
+//! 24-byte nonce. Frames are read in order, so the reader sees every frame
+//! sealed with the old key before it switches. Each direction is rekeyed
+//! independently; to renew both, both peers call `rekey`. Both peers must
+//! support the extension.
+//!
+//! See the [protocol guide](https://ssbc.github.io/scuttlebutt-protocol-guide/#box-stream)
+//! for details.
+
+use core::cmp::min;
+use core::fmt;
+use core::pin::Pin;
+use std::io;
+
+use futures::future::poll_fn;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::task::{Context, Poll};
+
+use sodiumoxide::crypto::hash::sha512;
+use ssb_crypto::{secretbox, PublicKey};
+
+use crate::{HandshakeOutcome, NonceGen};
+use crate::nonce::Hex;
+
+/// Maximum number of plaintext bytes in one box stream frame.
+pub const MAX_FRAME_BODY_LEN: usize = 4096;
+
+// body length (u16, big-endian) + body auth tag
+const HEADER_PLAIN_LEN: usize = 2 + secretbox::MACBYTES;
+const HEADER_LEN: usize = HEADER_PLAIN_LEN + secretbox::MACBYTES;
+
+// Header of a rekey frame: body length 0xffff, zero tag
+const REKEY_HEADER: [u8; HEADER_PLAIN_LEN] = {
+    let mut h = [0; HEADER_PLAIN_LEN];
+    h[0] = 0xff;
+    h[1] = 0xff;
+    h
+};
+
+/// The key and nonce generator that follow `key` after a rekey frame.
+fn ratchet(key: &secretbox::Key) -> (secretbox::Key, NonceGen) {
+    let mut input = b"shs_async rekey".to_vec();
+    input.extend_from_slice(&key[..]);
+    let mut digest = sha512::hash(&input);
+    sodiumoxide::utils::memzero(&mut input);
+
+    let key = secretbox::Key::from_slice(&digest[..secretbox::KEYBYTES]).unwrap();
+    let nonce = secretbox::Nonce::from_slice(
+        &digest[secretbox::KEYBYTES..secretbox::KEYBYTES + secretbox::NONCEBYTES]).unwrap();
+    sodiumoxide::utils::memzero(&mut digest.0);
+    (key, NonceGen::with_starting_nonce(nonce))
+}
+
+/// Encrypted stream, wrapping the stream over which the handshake was done.
+///
+/// Writes are buffered, one frame at a time; call `flush` to make sure all
+/// written data has been sent to the underlying stream.
+pub struct BoxStream<S> {
+    inner: S,
+    peer_pk: PublicKey,
+    reader: BoxReader,
+    writer: BoxWriter,
+}
+
+/// Keys are redacted.
+impl<S: fmt::Debug> fmt::Debug for BoxStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BoxStream")
+            .field("inner", &self.inner)
+            .field("peer_pk", &Hex(&self.peer_pk[..]))
+            .field("read_key", &"<redacted>")
+            .field("read_noncegen", &self.reader.noncegen)
+            .field("write_key", &"<redacted>")
+            .field("write_noncegen", &self.writer.noncegen)
+            .finish()
+    }
+}
+
+impl<S> BoxStream<S> {
+    /// Wrap `stream`, using the keys and nonces from a completed handshake.
+    pub fn new(stream: S, outcome: HandshakeOutcome) -> BoxStream<S> {
+        BoxStream {
+            inner: stream,
+            peer_pk: outcome.peer_pk,
+            reader: BoxReader::new(outcome.read_key, outcome.read_noncegen),
+            writer: BoxWriter::new(outcome.write_key, outcome.write_noncegen),
+        }
+    }
+
+    /// The peer's long-term public key, as verified by the handshake.
+    pub fn peer_pk(&self) -> &PublicKey {
+        &self.peer_pk
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S> BoxStream<S>
+where S: AsyncWrite + Unpin
+{
+    /// Replace the write direction's key and nonces; see the
+    /// [module docs](self#rekeying). Any buffered data is sent first, under
+    /// the old key. Resolves once the rekey frame has been flushed.
+    pub async fn rekey(&mut self) -> Result<(), io::Error> {
+        let this = &mut *self;
+        // Whether this call has sealed its rekey frame. This is kept in the
+        // future rather than the writer: if the future is dropped, the frame
+        // is sent by a later write, and the next call must seal a new one.
+        let mut sealed = false;
+        poll_fn(|cx| this.writer.poll_rekey(Pin::new(&mut this.inner), cx, &mut sealed)).await
+    }
+}
+
+impl<S> AsyncRead for BoxStream<S>
+where S: AsyncRead + Unpin
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                 -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        this.reader.poll_read(Pin::new(&mut this.inner), cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for BoxStream<S>
+where S: AsyncWrite + Unpin
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                  -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        this.writer.poll_write(Pin::new(&mut this.inner), cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        this.writer.poll_flush(Pin::new(&mut this.inner), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        this.writer.poll_close(Pin::new(&mut this.inner), cx)
+    }
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn nonces_exhausted() -> io::Error {
+    io::Error::other("box stream nonces exhausted; the stream must be rekeyed or closed")
+}
+
+enum ReadState {
+    /// Reading an encrypted header into `buf`.
+    Header,
+    /// Reading an encrypted body into `buf`.
+    Body { len: usize, tag: secretbox::Tag },
+    /// `buf[pos..len]` holds decrypted bytes that haven't been read yet.
+    Plain { pos: usize, len: usize },
+    /// The peer sent a goodbye.
+    Goodbye,
+    /// A frame failed to decrypt or was malformed. Every later read fails
+    /// with the same error, as the stream can't be trusted past this point.
+    Poisoned(&'static str),
+}
+
+struct BoxReader {
+    key: secretbox::Key,
+    noncegen: NonceGen,
+    state: ReadState,
+    buf: Vec<u8>,
+    filled: usize,
+}
+
+impl BoxReader {
+    fn new(key: secretbox::Key, noncegen: NonceGen) -> BoxReader {
+        BoxReader {
+            key,
+            noncegen,
+            state: ReadState::Header,
+            buf: vec![0; MAX_FRAME_BODY_LEN],
+            filled: 0,
+        }
+    }
+
+    fn poll_read<R>(&mut self, mut inner: Pin<&mut R>, cx: &mut Context, out: &mut [u8])
+                    -> Poll<Result<usize, io::Error>>
+    where R: AsyncRead
+    {
+        if out.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            let want = match self.state {
+                ReadState::Plain { pos, len } => {
+                    let n = min(out.len(), len - pos);
+                    out[..n].copy_from_slice(&self.buf[pos..pos + n]);
+
+                    self.state = if pos + n == len {
+                        ReadState::Header
+                    } else {
+                        ReadState::Plain { pos: pos + n, len }
+                    };
+                    return Poll::Ready(Ok(n));
+                },
+                ReadState::Header => HEADER_LEN,
+                ReadState::Body { len, .. } => len,
+                ReadState::Goodbye => return Poll::Ready(Ok(0)),
+                ReadState::Poisoned(msg) => return Poll::Ready(Err(invalid_data(msg))),
+            };
+
+            // Read the rest of the header or body. Everything read so far is
+            // kept in `self`, so it's fine for this future to be dropped and
+            // polled again later.
+            while self.filled < want {
+                match inner.as_mut().poll_read(cx, &mut self.buf[self.filled..want]) {
+                    Poll::Ready(Ok(0)) => {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    },
+                    Poll::Ready(Ok(n)) => self.filled += n,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            self.filled = 0;
+
+            self.state = match self.state {
+                ReadState::Header => {
+                    let nonce = self.noncegen.try_next().ok_or_else(nonces_exhausted)?;
+                    let h = secretbox::open(&self.buf[..HEADER_LEN], &nonce, &self.key)
+                        .map_err(|_| self.poison("failed to decrypt box stream header"))?;
+
+                    if h.iter().all(|b| *b == 0) {
+                        self.state = ReadState::Goodbye;
+                        return Poll::Ready(Ok(0));
+                    }
+                    if h[..] == REKEY_HEADER[..] {
+                        let (key, noncegen) = ratchet(&self.key);
+                        self.key = key;
+                        self.noncegen = noncegen;
+                        self.state = ReadState::Header;
+                        continue;
+                    }
+
+                    let len = ((h[0] as usize) << 8) | h[1] as usize;
+                    if len > MAX_FRAME_BODY_LEN {
+                        return Poll::Ready(Err(self.poison("box stream frame too long")));
+                    }
+                    let tag = secretbox::Tag::from_slice(&h[2..]).unwrap();
+                    ReadState::Body { len, tag }
+                },
+
+                ReadState::Body { len, ref tag } => {
+                    let nonce = self.noncegen.try_next().ok_or_else(nonces_exhausted)?;
+                    secretbox::open_detached(&mut self.buf[..len], tag, &nonce, &self.key)
+                        .map_err(|_| self.poison("failed to decrypt box stream body"))?;
+                    // An empty frame isn't a goodbye; returning Ok(0) for it
+                    // would look like the end of the stream.
+                    if len == 0 {
+                        ReadState::Header
+                    } else {
+                        ReadState::Plain { pos: 0, len }
+                    }
+                },
+
+                ReadState::Plain { .. } | ReadState::Goodbye | ReadState::Poisoned(_) =>
+                    unreachable!(),
+            };
+        }
+    }
+
+    fn poison(&mut self, msg: &'static str) -> io::Error {
+        self.state = ReadState::Poisoned(msg);
+        invalid_data(msg)
+    }
+}
+
+struct BoxWriter {
+    key: secretbox::Key,
+    noncegen: NonceGen,
+    // Encrypted frame; `buf[pos..]` hasn't been written yet.
+    buf: Vec<u8>,
+    pos: usize,
+    goodbye_sent: bool,
+}
+
+impl BoxWriter {
+    fn new(key: secretbox::Key, noncegen: NonceGen) -> BoxWriter {
+        BoxWriter {
+            key,
+            noncegen,
+            buf: Vec::with_capacity(HEADER_LEN + MAX_FRAME_BODY_LEN),
+            pos: 0,
+            goodbye_sent: false,
+        }
+    }
+
+    fn seal_frame(&mut self, body: &[u8]) -> Result<(), io::Error> {
+        debug_assert!(body.len() <= MAX_FRAME_BODY_LEN);
+
+        // header, body, and the goodbye
+        if !self.noncegen.has_remaining(3) {
+            return Err(nonces_exhausted());
+        }
+        let header_nonce = self.noncegen.try_next().ok_or_else(nonces_exhausted)?;
+        let body_nonce = self.noncegen.try_next().ok_or_else(nonces_exhausted)?;
+
+        self.buf.clear();
+        self.buf.resize(HEADER_LEN, 0);
+        self.buf.extend_from_slice(body);
+        let tag = secretbox::seal_detached(&mut self.buf[HEADER_LEN..], &body_nonce, &self.key);
+
+        let mut h = [0u8; HEADER_PLAIN_LEN];
+        h[0] = (body.len() >> 8) as u8;
+        h[1] = body.len() as u8;
+        h[2..].copy_from_slice(&tag[..]);
+        let header = secretbox::seal(&h, &header_nonce, &self.key);
+        self.buf[..HEADER_LEN].copy_from_slice(&header);
+        self.pos = 0;
+        Ok(())
+    }
+
+    fn seal_goodbye(&mut self) -> Result<(), io::Error> {
+        let nonce = self.noncegen.try_next().ok_or_else(nonces_exhausted)?;
+        self.buf.clear();
+        self.buf.extend_from_slice(&secretbox::seal(&[0; HEADER_PLAIN_LEN], &nonce, &self.key));
+        self.pos = 0;
+        self.goodbye_sent = true;
+        Ok(())
+    }
+
+    /// Seal a rekey frame under the current key, then switch to the next one.
+    fn seal_rekey(&mut self) -> Result<(), io::Error> {
+        let nonce = self.noncegen.try_next().ok_or_else(nonces_exhausted)?;
+        self.buf.clear();
+        self.buf.extend_from_slice(&secretbox::seal(&REKEY_HEADER, &nonce, &self.key));
+        self.pos = 0;
+
+        let (key, noncegen) = ratchet(&self.key);
+        self.key = key;
+        self.noncegen = noncegen;
+        Ok(())
+    }
+
+    /// Write out any buffered frame.
+    fn poll_send<W>(&mut self, mut inner: Pin<&mut W>, cx: &mut Context)
+                    -> Poll<Result<(), io::Error>>
+    where W: AsyncWrite
+    {
+        while self.pos < self.buf.len() {
+            match inner.as_mut().poll_write(cx, &self.buf[self.pos..]) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                },
+                Poll::Ready(Ok(n)) => self.pos += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.buf.clear();
+        self.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_write<W>(&mut self, mut inner: Pin<&mut W>, cx: &mut Context, data: &[u8])
+                     -> Poll<Result<usize, io::Error>>
+    where W: AsyncWrite
+    {
+        match self.poll_send(inner.as_mut(), cx) {
+            Poll::Ready(Ok(())) => {},
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        if self.goodbye_sent {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                                                  "box stream has been closed")));
+        }
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = min(data.len(), MAX_FRAME_BODY_LEN);
+        self.seal_frame(&data[..n])?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush<W>(&mut self, mut inner: Pin<&mut W>, cx: &mut Context)
+                     -> Poll<Result<(), io::Error>>
+    where W: AsyncWrite
+    {
+        match self.poll_send(inner.as_mut(), cx) {
+            Poll::Ready(Ok(())) => inner.poll_flush(cx),
+            p => p,
+        }
+    }
+
+    fn poll_rekey<W>(&mut self, mut inner: Pin<&mut W>, cx: &mut Context, sealed: &mut bool)
+                     -> Poll<Result<(), io::Error>>
+    where W: AsyncWrite
+    {
+        if !*sealed {
+            match self.poll_send(inner.as_mut(), cx) {
+                Poll::Ready(Ok(())) => {},
+                p => return p,
+            }
+            if self.goodbye_sent {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                                                      "box stream has been closed")));
+            }
+            self.seal_rekey()?;
+            *sealed = true;
+        }
+        match self.poll_send(inner.as_mut(), cx) {
+            Poll::Ready(Ok(())) => inner.poll_flush(cx),
+            p => p,
+        }
+    }
+
+    fn poll_close<W>(&mut self, mut inner: Pin<&mut W>, cx: &mut Context)
+                     -> Poll<Result<(), io::Error>>
+    where W: AsyncWrite
+    {
+        if !self.goodbye_sent {
+            match self.poll_send(inner.as_mut(), cx) {
+                Poll::Ready(Ok(())) => self.seal_goodbye()?,
+                p => return p,
+            }
+        }
+        match self.poll_send(inner.as_mut(), cx) {
+            Poll::Ready(Ok(())) => inner.poll_close(cx),
+            p => p,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::join;
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_crypto::handshake::generate_ephemeral_keypair;
+    use ssb_crypto::secretbox::{gen_key, gen_nonce, Nonce};
+
+    extern crate async_ringbuffer;
+    use async_ringbuffer::{ring_buffer, Reader, Writer};
+
+    // One-way box stream, writer and reader ends.
+    fn box_pipe() -> (BoxStream<Writer>, BoxStream<Reader>) {
+        box_pipe_from(gen_nonce())
+    }
+
+    fn box_pipe_from(nonce: Nonce) -> (BoxStream<Writer>, BoxStream<Reader>) {
+        let (w, r) = ring_buffer(1024);
+        let key = gen_key();
+        let (peer_pk, _) = generate_longterm_keypair();
+        let (eph_pk, _) = generate_ephemeral_keypair();
+
+        let outcome = |read: bool| {
+            let (k, n) = (key.clone(), nonce);
+            if read {
+                HandshakeOutcome {
+                    read_key: k, read_noncegen: NonceGen::with_starting_nonce(n),
+                    write_key: gen_key(), write_noncegen: NonceGen::with_starting_nonce(gen_nonce()),
+                    peer_pk, client_eph_pk: eph_pk, server_eph_pk: eph_pk,
+                }
+            } else {
+                HandshakeOutcome {
+                    read_key: gen_key(), read_noncegen: NonceGen::with_starting_nonce(gen_nonce()),
+                    write_key: k, write_noncegen: NonceGen::with_starting_nonce(n),
+                    peer_pk, client_eph_pk: eph_pk, server_eph_pk: eph_pk,
+                }
+            }
+        };
+
+        (BoxStream::new(w, outcome(false)), BoxStream::new(r, outcome(true)))
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (mut w, mut r) = box_pipe();
+
+        let send = async {
+            w.write_all(b"hello box stream").await.unwrap();
+            w.flush().await.unwrap();
+        };
+        let recv = async {
+            let mut buf = [0; 16];
+            r.read_exact(&mut buf).await.unwrap();
+            buf
+        };
+        let (_, buf) = block_on(async { join!(send, recv) });
+        assert_eq!(&buf, b"hello box stream");
+    }
+
+    #[test]
+    fn large_write_small_reads() {
+        let (mut w, mut r) = box_pipe();
+
+        // Spans three frames
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+
+        let send = async {
+            w.write_all(&data).await.unwrap();
+            w.flush().await.unwrap();
+        };
+        let recv = async {
+            let mut out = Vec::new();
+            let mut buf = [0; 300];
+            while out.len() < data.len() {
+                let n = r.read(&mut buf).await.unwrap();
+                assert!(n > 0);
+                out.extend_from_slice(&buf[..n]);
+            }
+            out
+        };
+        let (_, out) = block_on(async { join!(send, recv) });
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn frames_are_split() {
+        let (mut w, _r) = box_pipe();
+        let data = [0u8; MAX_FRAME_BODY_LEN + 1];
+
+        let n = block_on(w.write(&data)).unwrap();
+        assert_eq!(n, MAX_FRAME_BODY_LEN);
+        assert_eq!(w.writer.buf.len(), HEADER_LEN + MAX_FRAME_BODY_LEN);
+    }
+
+    #[test]
+    fn goodbye() {
+        let (mut w, mut r) = box_pipe();
+
+        let send = async {
+            w.write_all(b"last words").await.unwrap();
+            w.close().await.unwrap();
+            assert!(w.write(b"more").await.is_err());
+        };
+        let recv = async {
+            let mut out = Vec::new();
+            r.read_to_end(&mut out).await.unwrap();
+
+            // Still at the end
+            let mut buf = [0; 4];
+            assert_eq!(r.read(&mut buf).await.unwrap(), 0);
+            out
+        };
+        let (_, out) = block_on(async { join!(send, recv) });
+        assert_eq!(out, b"last words");
+        assert!(r.get_ref().is_closed());
+    }
+
+    #[test]
+    fn empty_frame_is_skipped() {
+        let (mut w, mut r) = box_pipe();
+
+        let send = async {
+            // `write` never sends an empty frame, but other implementations may.
+            w.writer.seal_frame(&[]).unwrap();
+            w.flush().await.unwrap();
+            w.write_all(b"after empty").await.unwrap();
+            w.close().await.unwrap();
+        };
+        let recv = async {
+            assert_eq!(r.read(&mut []).await.unwrap(), 0);
+            let mut out = Vec::new();
+            r.read_to_end(&mut out).await.unwrap();
+            out
+        };
+        let ((), out) = block_on(async { join!(send, recv) });
+        assert_eq!(out, b"after empty");
+    }
+
+    #[test]
+    fn eof_without_goodbye() {
+        let (mut w, mut r) = box_pipe();
+
+        let send = async {
+            w.write_all(b"cut off").await.unwrap();
+            w.flush().await.unwrap();
+            // Close the ring buffer itself, without a goodbye.
+            w.get_mut().close().await.unwrap();
+        };
+        let recv = async {
+            let mut out = Vec::new();
+            r.read_to_end(&mut out).await
+        };
+        let (_, res) = block_on(async { join!(send, recv) });
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_is_cancel_safe() {
+        use futures::FutureExt;
+
+        let (mut w, mut r) = box_pipe();
+        w.writer.seal_frame(b"resumed after cancel").unwrap();
+        let frame = w.writer.buf.clone();
+        let raw_w = w.get_mut();
+        let mut buf = [0; 32];
+
+        // Drop the read future partway through the header, then partway
+        // through the body.
+        for part in &[&frame[..20], &frame[20..HEADER_LEN + 5]] {
+            block_on(raw_w.write_all(part)).unwrap();
+            assert!(r.read(&mut buf).now_or_never().is_none());
+        }
+        block_on(raw_w.write_all(&frame[HEADER_LEN + 5..])).unwrap();
+
+        let n = block_on(r.read(&mut buf)).unwrap();
+        assert_eq!(&buf[..n], b"resumed after cancel");
+    }
+
+    #[test]
+    fn tampered_body() {
+        let (mut w, mut r) = box_pipe();
+
+        let (mut raw_w, raw_r) = ring_buffer(1024);
+        r.inner = raw_r;
+
+        let recv = async {
+            let mut buf = [0; 5];
+            let first = r.read_exact(&mut buf).await;
+            (first, r.read(&mut buf).await)
+        };
+        // Moved in, so the raw stream is closed once the frame is sent.
+        let relay = async move {
+            // Encrypt a frame, flip a bit in the body, and pass it along.
+            let n = w.write(b"hello").await.unwrap();
+            assert_eq!(n, 5);
+            let mut frame = w.writer.buf.clone();
+            frame[HEADER_LEN + 1] ^= 1;
+            raw_w.write_all(&frame).await.unwrap();
+        };
+
+        let ((first, second), _) = block_on(async { join!(recv, relay) });
+        assert_eq!(first.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // The error sticks, rather than the next frame being read.
+        assert_eq!(second.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rekey() {
+        let (mut w, mut r) = box_pipe();
+        let data: Vec<u8> = (0..6000).map(|i| i as u8).collect();
+        let old_key = w.writer.key.clone();
+
+        let send = async {
+            w.write_all(&data).await.unwrap();
+            w.rekey().await.unwrap();
+            assert!(w.writer.key != old_key);
+            w.write_all(b"after rekey").await.unwrap();
+            w.rekey().await.unwrap();
+            w.rekey().await.unwrap();
+            w.write_all(b", twice").await.unwrap();
+            w.close().await.unwrap();
+        };
+        let recv = async {
+            let mut out = vec![];
+            r.read_to_end(&mut out).await.unwrap();
+            out
+        };
+        let ((), out) = block_on(async { join!(send, recv) });
+        assert_eq!(&out[..6000], &data[..]);
+        assert_eq!(&out[6000..], b"after rekey, twice");
+        assert!(r.reader.key == w.writer.key);
+    }
+
+    #[test]
+    fn rekey_after_dropped_rekey() {
+        use futures::FutureExt;
+
+        let (mut w, mut r) = box_pipe();
+        let once = ratchet(&w.writer.key).0;
+        let twice = ratchet(&once).0;
+
+        // A 1014-byte frame, leaving too little room in the ring buffer for
+        // the rekey frame, so the rekey is sealed but left pending.
+        block_on(async {
+            w.write_all(&[7; 980]).await.unwrap();
+            w.flush().await.unwrap();
+        });
+        assert!(w.rekey().now_or_never().is_none());
+        assert!(w.writer.key == once);
+
+        let send = async {
+            // Sends the rest of the first rekey frame
+            w.write_all(b"x").await.unwrap();
+            w.rekey().await.unwrap();
+            w.write_all(b"y").await.unwrap();
+            w.close().await.unwrap();
+        };
+        let recv = async {
+            let mut out = vec![];
+            r.read_to_end(&mut out).await.unwrap();
+            out
+        };
+        let ((), out) = block_on(async { join!(send, recv) });
+        assert_eq!(&out[..980], &[7; 980][..]);
+        assert_eq!(&out[980..], b"xy");
+        assert!(w.writer.key == twice);
+        assert!(r.reader.key == twice);
+    }
+
+    #[test]
+    fn rekey_renews_nonces() {
+        // Room for the rekey frame and the goodbye only
+        let mut start = [0xff; 24];
+        start[23] = 0xfe;
+        let (mut w, mut r) = box_pipe_from(Nonce(start));
+
+        let send = async {
+            assert!(w.write(b"no room").await.is_err());
+            w.rekey().await.unwrap();
+            w.write_all(b"room again").await.unwrap();
+            w.close().await.unwrap();
+        };
+        let recv = async {
+            let mut out = vec![];
+            r.read_to_end(&mut out).await.unwrap();
+            out
+        };
+        let ((), out) = block_on(async { join!(send, recv) });
+        assert_eq!(&out, b"room again");
+    }
+
+    #[test]
+    fn nonce_exhaustion() {
+        // Room for one frame (two nonces), and the goodbye
+        let mut start = [0xff; 24];
+        start[23] = 0xfd;
+        let (mut w, mut r) = box_pipe_from(Nonce(start));
+
+        let send = async {
+            w.write_all(b"last frame").await.unwrap();
+            w.flush().await.unwrap();
+            let e = w.write(b"one too many").await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::Other);
+            w.close().await.unwrap();
+            assert!(w.write(b"again").await.is_err());
+        };
+        let recv = async {
+            let mut out = vec![];
+            r.read_to_end(&mut out).await.unwrap();
+            out
+        };
+        let ((), out) = block_on(async { join!(send, recv) });
+        assert_eq!(&out, b"last frame");
+    }
+}
@@ -0,0 +1,70 @@
+use core::pin::Pin;
+use std::io;
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::task::{Context, Poll};
+use tokio::io::ReadBuf;
+
+/// Adapts a stream implementing tokio's io traits to the futures io traits.
+pub(crate) struct TokioCompat<S>(pub S);
+
+impl<S> AsyncRead for TokioCompat<S>
+where S: tokio::io::AsyncRead + Unpin
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                 -> Poll<Result<usize, io::Error>> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut self.get_mut().0).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.filled().len())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S> AsyncWrite for TokioCompat<S>
+where S: tokio::io::AsyncWrite + Unpin
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                  -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use futures::join;
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[tokio::test]
+    async fn tokio_duplex_handshake() {
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let (mut c_stream, mut s_stream) = tokio::io::duplex(1024);
+
+        let client_side = client_tokio(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk);
+        let server_side = server_tokio(&mut s_stream, net_key, s_pk, s_sk);
+
+        let (c_out, s_out) = join!(client_side, server_side);
+        let mut c_out = c_out.unwrap();
+        let mut s_out = s_out.unwrap();
+
+        assert_eq!(c_out.peer_pk, s_pk);
+        assert_eq!(s_out.peer_pk, c_pk);
+        assert_eq!(c_out.write_key, s_out.read_key);
+        assert_eq!(c_out.read_key, s_out.write_key);
+        assert_eq!(c_out.write_noncegen.next(), s_out.read_noncegen.next());
+        assert_eq!(c_out.read_noncegen.next(), s_out.write_noncegen.next());
+    }
+}
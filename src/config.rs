@@ -0,0 +1,18 @@
+use core::time::Duration;
+use std::time::Instant;
+
+/// Time limits for [`client_with_config`](crate::client_with_config) and
+/// [`server_with_config`](crate::server_with_config).
+///
+/// The two limits complement each other: `timeout` catches a peer that
+/// stops responding, and `deadline` catches one that responds just often
+/// enough to never trip the timeout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HandshakeConfig {
+    /// Fail with `HandshakeError::TimedOut` if any read or write on the
+    /// stream makes no progress for this long.
+    pub timeout: Option<Duration>,
+    /// Fail with `HandshakeError::DeadlineExceeded` if the handshake hasn't
+    /// completed by this time, however the peer paces its messages.
+    pub deadline: Option<Instant>,
+}
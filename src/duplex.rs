@@ -0,0 +1,39 @@
+use core::pin::Pin;
+use std::io;
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::task::{Context, Poll};
+
+/// Joins separate reader and writer halves into a single stream.
+pub(crate) struct Duplex<R, W> {
+    pub r: R,
+    pub w: W,
+}
+
+impl<R, W> AsyncRead for Duplex<R, W>
+where R: AsyncRead + Unpin,
+      W: Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                 -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.get_mut().r).poll_read(cx, buf)
+    }
+}
+
+impl<R, W> AsyncWrite for Duplex<R, W>
+where R: Unpin,
+      W: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                  -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.get_mut().w).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().w).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().w).poll_close(cx)
+    }
+}
@@ -0,0 +1,186 @@
+//! Parsing and formatting of keys in the base64 formats used by SSB config
+//! and secret files.
+
+use std::error;
+use std::fmt;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sodiumoxide::crypto::sign::{PUBLICKEYBYTES, SECRETKEYBYTES};
+use ssb_crypto::{NetworkKey, PublicKey, SecretKey};
+
+const ED25519_SUFFIX: &str = ".ed25519";
+
+/// Why a key string couldn't be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyParseError {
+    /// The string (or the part of it that holds the key) isn't valid base64
+    InvalidBase64,
+    /// The decoded key has the wrong number of bytes
+    WrongLength { expected: usize, got: usize },
+    /// A feed id didn't start with `@`
+    MissingSigil,
+    /// The key didn't end with `.ed25519`
+    BadSuffix,
+}
+
+impl fmt::Display for KeyParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use KeyParseError::*;
+        match self {
+            InvalidBase64 => write!(f, "Key is not valid base64"),
+            WrongLength { expected, got } =>
+                write!(f, "Expected a key of {} bytes, got {} bytes", expected, got),
+            MissingSigil => write!(f, "Feed id doesn't start with '@'"),
+            BadSuffix => write!(f, "Key doesn't end with '{}'", ED25519_SUFFIX),
+        }
+    }
+}
+
+impl error::Error for KeyParseError {}
+
+/// A network key of the wrong length was given to [`network_key_from_slice`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidNetworkKey {
+    /// Number of bytes given
+    pub got: usize,
+}
+
+impl fmt::Display for InvalidNetworkKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "network key must be {} bytes, got {}", NetworkKey::size(), self.got)
+    }
+}
+
+impl error::Error for InvalidNetworkKey {}
+
+impl From<InvalidNetworkKey> for KeyParseError {
+    fn from(err: InvalidNetworkKey) -> KeyParseError {
+        KeyParseError::WrongLength { expected: NetworkKey::size(), got: err.got }
+    }
+}
+
+/// A network key from its raw bytes, which must be exactly 32 bytes long.
+pub fn network_key_from_slice(bytes: &[u8]) -> Result<NetworkKey, InvalidNetworkKey> {
+    NetworkKey::from_slice(bytes).ok_or(InvalidNetworkKey { got: bytes.len() })
+}
+
+fn decode_base64(s: &str) -> Result<Vec<u8>, KeyParseError> {
+    STANDARD.decode(s).map_err(|_| KeyParseError::InvalidBase64)
+}
+
+fn decode(s: &str, expected: usize) -> Result<Vec<u8>, KeyParseError> {
+    let bytes = decode_base64(s)?;
+    if bytes.len() == expected {
+        Ok(bytes)
+    } else {
+        Err(KeyParseError::WrongLength { expected, got: bytes.len() })
+    }
+}
+
+/// Parse a base64 network key (the `caps.shs` value of an SSB config).
+pub fn network_key_from_base64(s: &str) -> Result<NetworkKey, KeyParseError> {
+    Ok(network_key_from_slice(&decode_base64(s)?)?)
+}
+
+/// The network key as base64.
+pub fn network_key_to_base64(key: &NetworkKey) -> String {
+    STANDARD.encode(key.as_slice())
+}
+
+/// Parse a public key from an SSB feed id, eg. `@<base64>=.ed25519`.
+pub fn public_key_from_ssb_id(s: &str) -> Result<PublicKey, KeyParseError> {
+    let s = s.strip_prefix('@').ok_or(KeyParseError::MissingSigil)?;
+    let s = s.strip_suffix(ED25519_SUFFIX).ok_or(KeyParseError::BadSuffix)?;
+    let bytes = decode(s, PUBLICKEYBYTES)?;
+    Ok(PublicKey::from_slice(&bytes).unwrap())
+}
+
+/// The SSB feed id of a public key.
+pub fn ssb_id(pk: &PublicKey) -> String {
+    format!("@{}{}", STANDARD.encode(&pk[..]), ED25519_SUFFIX)
+}
+
+/// Parse a secret key in the format of the `private` field of an SSB
+/// secret file: `<base64>.ed25519`.
+pub fn secret_key_from_base64(s: &str) -> Result<SecretKey, KeyParseError> {
+    let s = s.strip_suffix(ED25519_SUFFIX).ok_or(KeyParseError::BadSuffix)?;
+    let mut bytes = decode(s, SECRETKEYBYTES)?;
+    let sk = SecretKey::from_slice(&bytes).unwrap();
+    sodiumoxide::utils::memzero(&mut bytes);
+    Ok(sk)
+}
+
+/// The secret key in the format of [`secret_key_from_base64`].
+pub fn secret_key_to_base64(sk: &SecretKey) -> String {
+    format!("{}{}", STANDARD.encode(&sk[..]), ED25519_SUFFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Keypair;
+
+    #[test]
+    fn network_key() {
+        let main = "1KHLiKZvAvjbY1ziZEHMXawbCEIM6qwjCDm3VYRan/s=";
+        assert_eq!(network_key_from_base64(main), Ok(NetworkKey::SSB_MAIN_NET));
+        assert_eq!(network_key_to_base64(&NetworkKey::SSB_MAIN_NET), main);
+
+        assert_eq!(network_key_from_base64("not base64!"), Err(KeyParseError::InvalidBase64));
+        assert_eq!(network_key_from_base64("AAAA"),
+                   Err(KeyParseError::WrongLength { expected: 32, got: 3 }));
+    }
+
+    #[test]
+    fn network_key_length() {
+        assert_eq!(network_key_from_slice(NetworkKey::SSB_MAIN_NET.as_slice()),
+                   Ok(NetworkKey::SSB_MAIN_NET));
+
+        let e = network_key_from_slice(&[0; 16]).unwrap_err();
+        assert_eq!(e, InvalidNetworkKey { got: 16 });
+        assert_eq!(e.to_string(), "network key must be 32 bytes, got 16");
+
+        assert_eq!(network_key_from_slice(&[0; 33]), Err(InvalidNetworkKey { got: 33 }));
+        assert_eq!(network_key_from_slice(&[]), Err(InvalidNetworkKey { got: 0 }));
+    }
+
+    #[test]
+    fn feed_id() {
+        let id = "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519";
+        let pk = public_key_from_ssb_id(id).unwrap();
+        assert_eq!(hex::encode(&pk[..]),
+                   "1425ffb6c0cba6e6c23ca29f22bc3881cf924241dc683d7bb3b188ea2ff38966");
+        assert_eq!(ssb_id(&pk), id);
+
+        let kp = Keypair::generate();
+        assert_eq!(public_key_from_ssb_id(&ssb_id(&kp.public)).unwrap(), kp.public);
+    }
+
+    #[test]
+    fn bad_feed_ids() {
+        use KeyParseError::*;
+        let b64 = "FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=";
+
+        assert_eq!(public_key_from_ssb_id(&format!("{}.ed25519", b64)), Err(MissingSigil));
+        assert_eq!(public_key_from_ssb_id(&format!("@{}.sha256", b64)), Err(BadSuffix));
+        assert_eq!(public_key_from_ssb_id(&format!("@{}", b64)), Err(BadSuffix));
+        assert_eq!(public_key_from_ssb_id(&format!("%{}.ed25519", b64)), Err(MissingSigil));
+        assert_eq!(public_key_from_ssb_id("@AAAA.ed25519"),
+                   Err(WrongLength { expected: 32, got: 3 }));
+        assert_eq!(public_key_from_ssb_id("@F?X.ed25519"), Err(InvalidBase64));
+    }
+
+    #[test]
+    fn secret_key() {
+        let kp = Keypair::from_seed(&[7; 32]);
+        let s = secret_key_to_base64(&kp.secret);
+        assert!(s.ends_with(".ed25519"));
+        assert_eq!(secret_key_from_base64(&s).unwrap(), kp.secret);
+
+        assert_eq!(secret_key_from_base64(s.trim_end_matches(".ed25519")),
+                   Err(KeyParseError::BadSuffix));
+        assert_eq!(secret_key_from_base64("AAAA.ed25519"),
+                   Err(KeyParseError::WrongLength { expected: 64, got: 3 }));
+    }
+}
@@ -0,0 +1,215 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use shs_core::HandshakeError as CoreError;
+
+use crate::timeout::IdleTimeout;
+
+/// The four messages of the handshake, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeStep {
+    ClientHello,
+    ServerHello,
+    ClientAuth,
+    ServerAccept,
+}
+
+impl fmt::Display for HandshakeStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            HandshakeStep::ClientHello => "client hello",
+            HandshakeStep::ServerHello => "server hello",
+            HandshakeStep::ClientAuth => "client auth",
+            HandshakeStep::ServerAccept => "server accept",
+        })
+    }
+}
+
+/// Why a handshake failed, including the step at which it failed.
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// IO error on the underlying stream
+    Io(io::Error),
+
+    /// A message was handed to the state machine with the wrong number of
+    /// bytes. Handshake messages have fixed sizes, so this happens when
+    /// driving the state machines directly, or when a framed transport
+    /// (`client_framed`/`server_framed`) delivers a wrongly sized frame.
+    MalformedMessage { step: HandshakeStep, expected: usize, got: usize },
+
+    /// The peer closed the connection while we were waiting for the given
+    /// message. A peer usually does this when it has rejected us (eg. for
+    /// using the wrong network key, or for not being authorized), as the
+    /// protocol has no way of saying why.
+    PeerClosedDuringHandshake { step: HandshakeStep },
+
+    /// (server) The client hello was malformed
+    ClientHelloInvalid,
+    /// (server) The client hello wasn't authenticated with our network key;
+    /// the client is probably on a different network
+    ClientHelloVerifyFailed,
+    /// (server) The client hello wasn't authenticated with any of the
+    /// network keys the server accepts
+    UnknownNetwork,
+
+    /// (client) The server hello was malformed
+    ServerHelloInvalid,
+    /// (client) The server hello wasn't authenticated with our network key;
+    /// the server is probably on a different network
+    ServerHelloVerifyFailed,
+
+    /// (server) The client auth message couldn't be decrypted, or its
+    /// signature didn't verify
+    ClientAuthRejected,
+    /// (server) The client's identity was verified, but the server's
+    /// authorization check rejected it
+    Unauthorized,
+
+    /// (client) The server accept message couldn't be decrypted, or its
+    /// signature didn't verify. This usually means that the server's
+    /// long-term key isn't the one we expected.
+    ServerAcceptRejected,
+
+    /// A key exchange with one of the peer's public keys failed
+    SharedSecretInvalid,
+
+    /// The peer didn't respond in time
+    TimedOut,
+
+    /// The handshake didn't complete before its overall deadline
+    DeadlineExceeded,
+
+    /// The handshake was aborted by the caller
+    Aborted,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use HandshakeError::*;
+        match self {
+            Io(e) => write!(f, "IO error during handshake: {}", e),
+            MalformedMessage { step, expected, got } =>
+                write!(f, "Expected a {} of {} bytes, got {} bytes", step, expected, got),
+            PeerClosedDuringHandshake { step } =>
+                write!(f, "Peer closed the connection instead of sending the {} (did it reject us?)", step),
+            ClientHelloInvalid => write!(f, "Received a malformed client hello"),
+            ClientHelloVerifyFailed =>
+                write!(f, "Client hello failed verification (is the client using a different network key?)"),
+            UnknownNetwork =>
+                write!(f, "Client hello failed verification with every accepted network key"),
+            ServerHelloInvalid => write!(f, "Received a malformed server hello"),
+            ServerHelloVerifyFailed =>
+                write!(f, "Server hello failed verification (is the server using a different network key?)"),
+            ClientAuthRejected => write!(f, "Client authentication failed"),
+            Unauthorized => write!(f, "Client is not authorized"),
+            ServerAcceptRejected =>
+                write!(f, "Server accept failed verification (is the server's public key correct?)"),
+            SharedSecretInvalid => write!(f, "Key exchange with peer's public key failed"),
+            TimedOut => write!(f, "Handshake timed out"),
+            DeadlineExceeded => write!(f, "Handshake didn't complete before its deadline"),
+            Aborted => write!(f, "Handshake was aborted"),
+        }
+    }
+}
+
+impl error::Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            HandshakeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Only our own timeouts become `HandshakeError::TimedOut`; a timeout
+/// reported by the stream itself (eg. `ETIMEDOUT`) stays an `Io` error.
+impl From<io::Error> for HandshakeError {
+    fn from(err: io::Error) -> HandshakeError {
+        match err.get_ref() {
+            Some(e) if e.is::<IdleTimeout>() => HandshakeError::TimedOut,
+            _ => HandshakeError::Io(err),
+        }
+    }
+}
+
+impl HandshakeError {
+    /// Error for a failed read of the peer's next message, `step`.
+    pub(crate) fn from_read(err: io::Error, step: Option<HandshakeStep>) -> HandshakeError {
+        match (err.kind(), step) {
+            (io::ErrorKind::UnexpectedEof, Some(step)) =>
+                HandshakeError::PeerClosedDuringHandshake { step },
+            _ => err.into(),
+        }
+    }
+}
+
+impl From<CoreError> for HandshakeError {
+    fn from(err: CoreError) -> HandshakeError {
+        use HandshakeError::*;
+        match err {
+            CoreError::Io(e) => e.into(),
+
+            CoreError::ClientHelloDeserializeFailed => ClientHelloInvalid,
+            CoreError::ClientHelloVerifyFailed => ClientHelloVerifyFailed,
+
+            CoreError::ServerHelloDeserializeFailed => ServerHelloInvalid,
+            CoreError::ServerHelloVerifyFailed => ServerHelloVerifyFailed,
+
+            CoreError::ClientAuthDeserializeFailed
+                | CoreError::ClientAuthOpenFailed
+                | CoreError::ClientAuthVerifyFailed => ClientAuthRejected,
+
+            CoreError::ServerAcceptDeserializeFailed
+                | CoreError::ServerAcceptOpenFailed
+                | CoreError::ServerAcceptVerifyFailed => ServerAcceptRejected,
+
+            CoreError::SharedAInvalid
+                | CoreError::SharedBInvalid
+                | CoreError::SharedCInvalid => SharedSecretInvalid,
+        }
+    }
+}
+
+impl From<HandshakeError> for io::Error {
+    fn from(err: HandshakeError) -> io::Error {
+        match err {
+            HandshakeError::Io(err) => err,
+            HandshakeError::TimedOut => io::Error::new(io::ErrorKind::TimedOut, IdleTimeout),
+            err @ HandshakeError::DeadlineExceeded => io::Error::new(io::ErrorKind::TimedOut, err),
+            err @ HandshakeError::Unauthorized => io::Error::new(io::ErrorKind::PermissionDenied, err),
+            err @ HandshakeError::Aborted => io::Error::new(io::ErrorKind::ConnectionAborted, err),
+            err @ HandshakeError::PeerClosedDuringHandshake { .. } =>
+                io::Error::new(io::ErrorKind::UnexpectedEof, err),
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_core_error() {
+        match HandshakeError::from(CoreError::ServerAcceptOpenFailed) {
+            HandshakeError::ServerAcceptRejected => {},
+            e => panic!("{:?}", e),
+        }
+        let e: HandshakeError = CoreError::Io(io::ErrorKind::UnexpectedEof.into()).into();
+        match e {
+            HandshakeError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            e => panic!("{:?}", e),
+        }
+        let e: HandshakeError = io::Error::new(io::ErrorKind::TimedOut, IdleTimeout).into();
+        match e {
+            HandshakeError::TimedOut => {},
+            e => panic!("{:?}", e),
+        }
+        let e: HandshakeError = io::Error::from(io::ErrorKind::TimedOut).into();
+        match e {
+            HandshakeError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            e => panic!("{:?}", e),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::io;
+
+use futures::sink::{Sink, SinkExt};
+use futures::stream::{Stream, StreamExt};
+use ssb_crypto::{NetworkKey, PublicKey, SecretKey};
+
+use crate::{HandshakeError, HandshakeOutcome};
+use crate::state::{self, ClientHandshake, Handshake, ServerHandshake, Step};
+
+/// Like [`client`](crate::client), but over a message-oriented transport
+/// (eg. WebSocket frames): each handshake message is sent as one item to
+/// `sink`, and each item of `stream` must be exactly one handshake message.
+///
+/// A frame of the wrong length fails with `HandshakeError::MalformedMessage`;
+/// the end of `stream` fails with `HandshakeError::PeerClosedDuringHandshake`.
+pub async fn client_framed<St, Si>(stream: St,
+                                   sink: Si,
+                                   net_key: NetworkKey,
+                                   pk: PublicKey,
+                                   sk: SecretKey,
+                                   server_pk: PublicKey)
+                                   -> Result<HandshakeOutcome, HandshakeError>
+where St: Stream<Item = Vec<u8>> + Unpin,
+      Si: Sink<Vec<u8>> + Unpin,
+      Si::Error: Into<io::Error>,
+{
+    let hs = ClientHandshake::new(net_key, pk, sk, server_pk);
+    run_framed(stream, sink, hs).await
+}
+
+/// Like [`server`](crate::server), but over a message-oriented transport;
+/// see [`client_framed`].
+pub async fn server_framed<St, Si>(stream: St,
+                                   sink: Si,
+                                   net_key: NetworkKey,
+                                   pk: PublicKey,
+                                   sk: SecretKey)
+                                   -> Result<HandshakeOutcome, HandshakeError>
+where St: Stream<Item = Vec<u8>> + Unpin,
+      Si: Sink<Vec<u8>> + Unpin,
+      Si::Error: Into<io::Error>,
+{
+    let hs = ServerHandshake::new(net_key, pk, sk);
+    run_framed(stream, sink, hs).await
+}
+
+/// Runs the handshake to completion. Like `run_abortable`, on failure the
+/// state machine is dropped before the sink is closed.
+async fn run_framed<St, Si, H>(mut stream: St, mut sink: Si, mut hs: H)
+                               -> Result<HandshakeOutcome, HandshakeError>
+where St: Stream<Item = Vec<u8>> + Unpin,
+      Si: Sink<Vec<u8>> + Unpin,
+      Si::Error: Into<io::Error>,
+      H: Handshake,
+{
+    let r = drive_framed(&mut stream, &mut sink, &mut hs).await;
+    drop(hs);
+    if r.is_err() {
+        sink.close().await.unwrap_or(());
+    }
+    r
+}
+
+async fn drive_framed<St, Si, H>(mut stream: St, mut sink: Si, mut hs: H)
+                                 -> Result<HandshakeOutcome, HandshakeError>
+where St: Stream<Item = Vec<u8>> + Unpin,
+      Si: Sink<Vec<u8>> + Unpin,
+      Si::Error: Into<io::Error>,
+      H: Handshake,
+{
+    let mut outcome = None;
+    loop {
+        if let Some(msg) = hs.next_message() {
+            let r = sink.send(msg.to_vec()).await.map_err(Into::<io::Error>::into);
+            r.map_err(|e| state::fail(&hs, e))?;
+            hs.message_sent();
+        }
+        if let Some(o) = outcome {
+            return Ok(o);
+        }
+
+        let frame = match stream.next().await {
+            Some(f) => f,
+            None => {
+                let step = hs.expected_step().unwrap();
+                return Err(state::fail(&hs, HandshakeError::PeerClosedDuringHandshake { step }));
+            },
+        };
+        if let Step::Done(o) = hs.receive(&frame)? {
+            outcome = Some(o);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::executor::block_on;
+    use futures::join;
+    use ssb_crypto::generate_longterm_keypair;
+
+    use crate::HandshakeStep;
+
+    type FrameSink = futures::sink::SinkMapErr<mpsc::Sender<Vec<u8>>,
+                                               fn(mpsc::SendError) -> io::Error>;
+
+    fn channel() -> (FrameSink, mpsc::Receiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::channel(4);
+        (tx.sink_map_err(io::Error::other as fn(_) -> _), rx)
+    }
+
+    #[test]
+    fn handshake_over_frames() {
+        let (c2s_tx, c2s_rx) = channel();
+        let (s2c_tx, s2c_rx) = channel();
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let client_side = client_framed(s2c_rx, c2s_tx, net_key.clone(), c_pk, c_sk, s_pk);
+        let server_side = server_framed(c2s_rx, s2c_tx, net_key, s_pk, s_sk);
+        let (c_out, s_out) = block_on(async { join!(client_side, server_side) });
+
+        let (c_out, s_out) = (c_out.unwrap(), s_out.unwrap());
+        assert_eq!(c_out.write_key, s_out.read_key);
+        assert_eq!(c_out.read_key, s_out.write_key);
+        assert_eq!(c_out.peer_pk, s_pk);
+        assert_eq!(s_out.peer_pk, c_pk);
+    }
+
+    #[test]
+    fn short_frame() {
+        let (s2c_tx, _s2c_rx) = channel();
+        let (s_pk, s_sk) = generate_longterm_keypair();
+
+        let frames = futures::stream::iter(vec![vec![0; 63]]);
+        match block_on(server_framed(frames, s2c_tx, NetworkKey::SSB_MAIN_NET, s_pk, s_sk)) {
+            Err(HandshakeError::MalformedMessage { step, expected, got }) =>
+                assert_eq!((step, expected, got), (HandshakeStep::ClientHello, 64, 63)),
+            r => panic!("{:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn sink_closed_on_failure() {
+        let (mut tx, _rx) = mpsc::channel(4);
+        let sink = (&mut tx).sink_map_err(io::Error::other as fn(mpsc::SendError) -> io::Error);
+        let (s_pk, s_sk) = generate_longterm_keypair();
+
+        let frames = futures::stream::iter(vec![vec![0; 64]]);
+        match block_on(server_framed(frames, sink, NetworkKey::SSB_MAIN_NET, s_pk, s_sk)) {
+            Err(HandshakeError::ClientHelloInvalid)
+                | Err(HandshakeError::ClientHelloVerifyFailed) => {},
+            r => panic!("{:?}", r.err()),
+        }
+        assert!(tx.is_closed());
+    }
+
+    #[test]
+    fn stream_ends() {
+        let (c2s_tx, _c2s_rx) = channel();
+        let (s_pk, _) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+
+        let frames = futures::stream::empty();
+        match block_on(client_framed(frames, c2s_tx, NetworkKey::SSB_MAIN_NET, c_pk, c_sk, s_pk)) {
+            Err(HandshakeError::PeerClosedDuringHandshake { step }) =>
+                assert_eq!(step, HandshakeStep::ServerHello),
+            r => panic!("{:?}", r.err()),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use sodiumoxide::crypto::sign;
+use ssb_crypto::{generate_longterm_keypair, PublicKey, SecretKey};
+#[cfg(feature = "rand_core")]
+use ssb_crypto::handshake::{EphPublicKey, EphSecretKey};
+
+/// A long-term identity: an ed25519 keypair.
+#[derive(Clone, Debug)]
+pub struct Keypair {
+    pub public: PublicKey,
+    pub secret: SecretKey,
+}
+
+impl Keypair {
+    /// Generate a new random keypair.
+    pub fn generate() -> Keypair {
+        let (public, secret) = generate_longterm_keypair();
+        Keypair { public, secret }
+    }
+
+    /// Derive the keypair from a 32-byte seed.
+    pub fn from_seed(seed: &[u8; 32]) -> Keypair {
+        let (public, secret) = sign::keypair_from_seed(&sign::Seed(*seed));
+        Keypair { public, secret }
+    }
+}
+
+/// Generate an ephemeral (curve25519) keypair, with the secret key drawn
+/// from `rng`.
+#[cfg(feature = "rand_core")]
+pub(crate) fn generate_eph_keypair<R>(rng: &mut R) -> (EphPublicKey, EphSecretKey)
+where R: rand_core::RngCore + rand_core::CryptoRng
+{
+    let mut bytes = [0; 32];
+    rng.fill_bytes(&mut bytes);
+    let sk = EphSecretKey(bytes);
+    sodiumoxide::utils::memzero(&mut bytes);
+    (sk.public_key(), sk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::FromHex;
+
+    #[test]
+    fn from_seed() {
+        // RFC 8032 ed25519 test key 1
+        let seed = <[u8; 32]>::from_hex(
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60").unwrap();
+        let kp = Keypair::from_seed(&seed);
+        assert_eq!(hex::encode(&kp.public[..]),
+                   "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        assert_eq!(&kp.secret[..32], &seed[..]);
+        assert_eq!(Keypair::from_seed(&seed).public, kp.public);
+    }
+
+    #[test]
+    fn debug_hides_secret() {
+        let kp = Keypair::generate();
+        assert!(format!("{:?}", kp).contains("SecretKey(****)"));
+    }
+}
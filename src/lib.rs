@@ -1,22 +1,221 @@
-#![feature(async_await, await_macro)]
+//! Async secret-handshake protocol, for Secure Scuttlebutt (SSB).
+//!
+//! The key types and key generation functions needed to call the handshake
+//! are re-exported here, so there's no need to depend on `ssb-crypto` or
+//! `shs_core` directly:
+//!
+//! ```
+//! use shs_async::{generate_longterm_keypair, network_key_from_base64, Keypair, NetworkKey};
+//!
+//! let (pk, sk) = generate_longterm_keypair();
+//! let keypair = Keypair { public: pk, secret: sk };
+//!
+//! let net_key = network_key_from_base64("1KHLiKZvAvjbY1ziZEHMXawbCEIM6qwjCDm3VYRan/s=").unwrap();
+//! assert_eq!(net_key, NetworkKey::SSB_MAIN_NET);
+//! # let _ = keypair;
+//! ```
+//!
+//! See [`client`] and [`server`] for the handshake itself.
 
 extern crate futures;
 extern crate shs_core;
 
+use core::future::Future;
+use core::time::Duration;
+use std::time::Instant;
+use futures::future::{self, Either, FutureExt};
 use futures::io::{
     AsyncRead,
     AsyncReadExt,
     AsyncWrite,
     AsyncWriteExt,
 };
-use core::mem::size_of;
 
-use ssb_crypto::{NetworkKey, NonceGen, PublicKey, SecretKey};
-use shs_core::{*, messages::*};
+use shs_core::messages::*;
 
-pub use shs_core::HandshakeError;
+// Types and functions from the crypto crates that appear in this crate's API.
+pub use ssb_crypto::{generate_longterm_keypair, NetworkKey, PublicKey, SecretKey};
+pub use ssb_crypto::handshake::{generate_ephemeral_keypair, EphPublicKey, EphSecretKey};
+pub use ssb_crypto::secretbox;
+#[cfg(feature = "export_ephemeral_INSECURE")]
+pub use shs_core::{ClientEphSecretKey, ServerEphSecretKey};
 
-pub async fn client<S>(mut stream: S,
+mod error;
+pub use error::{HandshakeError, HandshakeStep};
+
+mod outcome;
+pub use outcome::{HandshakeOutcome, ReadHalf, WriteHalf};
+
+mod keypair;
+pub use keypair::Keypair;
+
+mod encoding;
+pub use encoding::{
+    network_key_from_base64,
+    network_key_from_slice,
+    network_key_to_base64,
+    public_key_from_ssb_id,
+    secret_key_from_base64,
+    secret_key_to_base64,
+    ssb_id,
+    InvalidNetworkKey,
+    KeyParseError,
+};
+
+mod nonce;
+pub use nonce::NonceGen;
+
+pub mod boxstream;
+pub use boxstream::BoxStream;
+
+pub mod state;
+use state::{ClientHandshake, Handshake, ServerHandshake, Step};
+
+mod timeout;
+pub use timeout::Sleep;
+use timeout::Timeout;
+
+mod duplex;
+use duplex::Duplex;
+
+mod prefix;
+use prefix::Prefixed;
+
+mod retry;
+pub use retry::{connect_with_retry, RetryPolicy};
+
+mod timings;
+pub use timings::HandshakeTimings;
+
+mod config;
+pub use config::HandshakeConfig;
+
+mod framed;
+pub use framed::{client_framed, server_framed};
+
+mod trace;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+#[cfg(feature = "tokio")]
+mod compat;
+#[cfg(feature = "tokio")]
+use compat::TokioCompat;
+
+static WELL_KNOWN_NETWORKS: &[(&str, NetworkKey)] = &[
+    ("ssb-main", NetworkKey::SSB_MAIN_NET),
+];
+
+/// Named network keys of well-known SSB networks.
+///
+/// This is a plain slice; an application that knows about other networks
+/// can chain its own `(name, key)` pairs onto it, e.g.
+/// `well_known_networks().iter().chain(my_networks.iter())`.
+pub fn well_known_networks() -> &'static [(&'static str, NetworkKey)] {
+    WELL_KNOWN_NETWORKS
+}
+
+/// Name of the given network key, if it's one of the `well_known_networks`.
+pub fn network_name(key: &NetworkKey) -> Option<&'static str> {
+    WELL_KNOWN_NETWORKS.iter()
+        .find(|(_, k)| k == key)
+        .map(|(name, _)| *name)
+}
+
+/// Which side of the handshake we're on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Number of bytes sent and received by one side of a complete handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HandshakeBytes {
+    pub sent: usize,
+    pub received: usize,
+}
+
+impl HandshakeBytes {
+    pub fn total(&self) -> usize {
+        self.sent + self.received
+    }
+}
+
+/// All handshake messages are of fixed size, so the number of bytes
+/// exchanged by a successful handshake is known ahead of time.
+pub fn expected_handshake_bytes(role: Role) -> HandshakeBytes {
+    let client_sends = ClientHello::size() + ClientAuth::size();
+    let server_sends = ServerHello::size() + ServerAccept::size();
+
+    match role {
+        Role::Client => HandshakeBytes { sent: client_sends, received: server_sends },
+        Role::Server => HandshakeBytes { sent: server_sends, received: client_sends },
+    }
+}
+
+/// Perform the client side of the handshake over `stream`, authenticating
+/// as `keypair` to the server whose public key is `server_pk`.
+///
+/// This is [`client`], with the long-term keypair passed as one value.
+pub async fn connect<S>(stream: S,
+                        net_key: NetworkKey,
+                        keypair: &Keypair,
+                        server_pk: PublicKey)
+                        -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    client(stream, net_key, keypair.public, keypair.secret.clone(), server_pk).await
+}
+
+/// Perform the server side of the handshake over `stream`, as `keypair`.
+///
+/// This is [`server`], with the long-term keypair passed as one value.
+pub async fn accept<S>(stream: S,
+                       net_key: NetworkKey,
+                       keypair: &Keypair)
+                       -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    server(stream, net_key, keypair.public, keypair.secret.clone()).await
+}
+
+/// Perform the client side of the handshake (see [`connect`]), and wrap
+/// `stream` in a [`BoxStream`] using the resulting keys.
+///
+/// The server's public key is available from `BoxStream::peer_pk`.
+pub async fn connect_boxed<S>(mut stream: S,
+                              net_key: NetworkKey,
+                              keypair: &Keypair,
+                              server_pk: PublicKey)
+                              -> Result<BoxStream<S>, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    let outcome = connect(&mut stream, net_key, keypair, server_pk).await?;
+    Ok(outcome.into_boxstream(stream))
+}
+
+/// Perform the server side of the handshake (see [`accept`]), and wrap
+/// `stream` in a [`BoxStream`] using the resulting keys.
+///
+/// The client's (verified) public key is available from `BoxStream::peer_pk`.
+pub async fn accept_boxed<S>(mut stream: S,
+                             net_key: NetworkKey,
+                             keypair: &Keypair)
+                             -> Result<BoxStream<S>, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    let outcome = accept(&mut stream, net_key, keypair).await?;
+    Ok(outcome.into_boxstream(stream))
+}
+
+/// Perform the client side of the handshake over `stream`.
+///
+/// The handshake starts at the stream's current position, so `stream` may
+/// already have been used for some plaintext exchange (eg. to negotiate
+/// whether to use shs at all). On failure, the stream is closed.
+pub async fn client<S>(stream: S,
                        net_key: NetworkKey,
                        pk: PublicKey,
                        sk: SecretKey,
@@ -24,135 +223,513 @@ pub async fn client<S>(mut stream: S,
                        -> Result<HandshakeOutcome, HandshakeError>
 where S: AsyncRead + AsyncWrite + Unpin
 {
-    let r = await!(try_client_side(&mut stream, net_key, pk, sk, server_pk));
-    if r.is_err() {
-        await!(stream.close()).unwrap_or(());
-    }
-    r
+    let hs = ClientHandshake::new(net_key, pk, sk, server_pk);
+    run_handshake(stream, hs).await
 }
 
-async fn try_client_side<S>(mut stream: S,
-                            net_key: NetworkKey,
-                            pk: PublicKey,
-                            sk: SecretKey,
-                            server_pk: PublicKey)
-                            -> Result<HandshakeOutcome, HandshakeError>
-where S: AsyncRead + AsyncWrite + Unpin
+/// Like [`client`], but with the stream's read and write sides given
+/// separately.
+pub async fn client_split<R, W>(reader: R,
+                                writer: W,
+                                net_key: NetworkKey,
+                                pk: PublicKey,
+                                sk: SecretKey,
+                                server_pk: PublicKey)
+                                -> Result<HandshakeOutcome, HandshakeError>
+where R: AsyncRead + Unpin,
+      W: AsyncWrite + Unpin,
 {
+    client(Duplex { r: reader, w: writer }, net_key, pk, sk, server_pk).await
+}
 
-    let pk = ClientPublicKey(pk);
-    let sk = ClientSecretKey(sk);
-    let server_pk = ServerPublicKey(server_pk);
-
-    let (eph_pk, eph_sk) = client::generate_eph_keypair();
-    let hello = ClientHello::new(&eph_pk, &net_key);
-    await!(stream.write_all(&hello.as_slice()))?;
-    await!(stream.flush())?;
+/// Like [`client`], but with `prefix` logically prepended to the stream's
+/// read side, for when some of the server's first message has already been
+/// read from the stream (eg. by a buffered reader). `prefix` must not
+/// extend past the server's messages; any bytes left over are discarded.
+pub async fn client_with_prefix<S>(stream: S,
+                                   prefix: &[u8],
+                                   net_key: NetworkKey,
+                                   pk: PublicKey,
+                                   sk: SecretKey,
+                                   server_pk: PublicKey)
+                                   -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    client(Prefixed { prefix, inner: stream }, net_key, pk, sk, server_pk).await
+}
 
-    let server_eph_pk = {
-        let mut buf = [0u8; size_of::<ServerHello>()];
-        await!(stream.read_exact(&mut buf))?;
+/// Like [`client`], but stops the handshake as soon as `abort` resolves
+/// (eg. on shutdown), closing the stream and returning `HandshakeError::Aborted`.
+pub async fn client_abortable<S, A>(stream: S,
+                                    net_key: NetworkKey,
+                                    pk: PublicKey,
+                                    sk: SecretKey,
+                                    server_pk: PublicKey,
+                                    abort: A)
+                                    -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      A: Future<Output = ()>,
+{
+    let hs = ClientHandshake::new(net_key, pk, sk, server_pk);
+    let abort = abort.map(|()| HandshakeError::Aborted);
+    run_abortable(stream, hs, abort, &mut HandshakeTimings::default()).await
+}
 
-        let server_hello = ServerHello::from_slice(&buf)?;
-        server_hello.verify(&net_key)?
-    };
+/// Like [`client`], but also reports how long each part of the handshake took.
+pub async fn client_timed<S>(stream: S,
+                             net_key: NetworkKey,
+                             pk: PublicKey,
+                             sk: SecretKey,
+                             server_pk: PublicKey)
+                             -> Result<(HandshakeOutcome, HandshakeTimings), HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut timings = HandshakeTimings::default();
+    let start = Instant::now();
+    let hs = ClientHandshake::new(net_key, pk, sk, server_pk);
+    timings.setup = start.elapsed();
 
-    // Derive shared secrets
-    let shared_a = SharedA::client_side(&eph_sk, &server_eph_pk)?;
-    let shared_b = SharedB::client_side(&eph_sk, &server_pk)?;
-    let shared_c = SharedC::client_side(&sk, &server_eph_pk)?;
+    let outcome = run_abortable(stream, hs, future::pending(), &mut timings).await?;
+    timings.total = start.elapsed();
+    Ok((outcome, timings))
+}
 
-    // Send client auth
-    let client_auth = ClientAuth::new(&sk, &pk, &server_pk, &net_key, &shared_a, &shared_b);
-    await!(stream.write_all(client_auth.as_slice()))?;
-    await!(stream.flush())?;
+/// Like [`client`], but with a caller-supplied ephemeral keypair
+/// (see `ssb_crypto::handshake::generate_ephemeral_keypair`), which makes
+/// the handshake deterministic.
+///
+/// Never reuse an ephemeral keypair outside of tests; the session's forward
+/// secrecy depends on it being fresh.
+pub async fn client_with_eph<S>(stream: S,
+                                net_key: NetworkKey,
+                                pk: PublicKey,
+                                sk: SecretKey,
+                                server_pk: PublicKey,
+                                eph_pk: EphPublicKey,
+                                eph_sk: EphSecretKey)
+                                -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    let hs = ClientHandshake::with_eph(net_key, pk, sk, server_pk, eph_pk, eph_sk);
+    run_handshake(stream, hs).await
+}
 
-    let mut buf = [0u8; 80];
-    await!(stream.read_exact(&mut buf))?;
+/// Like [`client`], but the ephemeral keypair is generated from `rng`
+/// instead of the system's randomness source.
+///
+/// Only available with the `rand_core` feature.
+#[cfg(feature = "rand_core")]
+pub async fn client_with_rng<S, R>(stream: S,
+                                   net_key: NetworkKey,
+                                   pk: PublicKey,
+                                   sk: SecretKey,
+                                   server_pk: PublicKey,
+                                   rng: &mut R)
+                                   -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      R: rand_core::RngCore + rand_core::CryptoRng,
+{
+    let (eph_pk, eph_sk) = keypair::generate_eph_keypair(rng);
+    client_with_eph(stream, net_key, pk, sk, server_pk, eph_pk, eph_sk).await
+}
 
-    let server_acc = ServerAccept::from_buffer(buf.to_vec())?;
-    server_acc.open_and_verify(&sk, &pk, &server_pk,
-                               &net_key, &shared_a,
-                               &shared_b, &shared_c)?;
+/// Like [`client`], but fails with `HandshakeError::TimedOut` if any read
+/// or write on the stream makes no progress for `timeout`.
+///
+/// `sleep` is used to create the timers, eg. `|d| tokio::time::sleep(d)`.
+/// The stream is closed on timeout, as with any other error.
+pub async fn client_with_timeout<S, Z>(stream: S,
+                                       net_key: NetworkKey,
+                                       pk: PublicKey,
+                                       sk: SecretKey,
+                                       server_pk: PublicKey,
+                                       timeout: Duration,
+                                       sleep: Z)
+                                       -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      Z: Sleep + Unpin,
+{
+    let hs = ClientHandshake::new(net_key, pk, sk, server_pk);
+    run_handshake(Timeout::new(stream, timeout, sleep), hs).await
+}
 
-    Ok(HandshakeOutcome {
-        read_key: server_to_client_key(&pk, &net_key, &shared_a, &shared_b, &shared_c),
-        read_noncegen: NonceGen::new(&eph_pk.0, &net_key),
+/// Like [`client`], but with the time limits in `config`.
+///
+/// `sleep` is used to create the timers; see [`client_with_timeout`].
+/// The stream is closed if either limit is hit, as with any other error.
+pub async fn client_with_config<S, Z>(stream: S,
+                                      net_key: NetworkKey,
+                                      pk: PublicKey,
+                                      sk: SecretKey,
+                                      server_pk: PublicKey,
+                                      config: HandshakeConfig,
+                                      sleep: Z)
+                                      -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      Z: Sleep + Unpin,
+{
+    let hs = ClientHandshake::new(net_key, pk, sk, server_pk);
+    run_with_config(stream, hs, config, sleep).await
+}
 
-        write_key: client_to_server_key(&server_pk, &net_key, &shared_a, &shared_b, &shared_c),
-        write_noncegen: NonceGen::new(&server_eph_pk.0, &net_key),
-    })
+/// Like [`client`], but also returns the client's ephemeral secret key.
+///
+/// **This is dangerous.** The ephemeral secret is what gives the session
+/// forward secrecy; anyone who obtains it (along with a recording of the
+/// handshake) can recover the session keys. It is exposed only for
+/// experimental protocols that continue the key schedule from it, and
+/// must never be stored, logged, or kept longer than strictly necessary.
+///
+/// Only available with the `export_ephemeral_INSECURE` feature.
+#[cfg(feature = "export_ephemeral_INSECURE")]
+pub async fn client_exporting_ephemeral<S>(stream: S,
+                                           net_key: NetworkKey,
+                                           pk: PublicKey,
+                                           sk: SecretKey,
+                                           server_pk: PublicKey)
+                                           -> Result<(HandshakeOutcome, ClientEphSecretKey), HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut hs = ClientHandshake::new(net_key, pk, sk, server_pk);
+    let outcome = run_handshake(stream, &mut hs).await?;
+    Ok((outcome, hs.into_ephemeral_secret()))
 }
 
-pub async fn server<S>(mut stream: S,
+/// Perform the server side of the handshake over `stream`.
+///
+/// As with [`client`], the handshake begins at the stream's current position.
+/// On failure, the stream is closed.
+pub async fn server<S>(stream: S,
                        net_key: NetworkKey,
                        pk: PublicKey,
                        sk: SecretKey)
                        -> Result<HandshakeOutcome, HandshakeError>
 where S: AsyncRead + AsyncWrite + Unpin
 {
-    let r = await!(try_server_side(&mut stream, net_key, pk, sk));
-    if r.is_err() {
-        await!(stream.close()).unwrap_or(());
-    }
-    r
+    let hs = ServerHandshake::new(net_key, pk, sk);
+    run_handshake(stream, hs).await
 }
 
-async fn try_server_side<S>(mut stream: S,
-                            net_key: NetworkKey,
-                            pk: PublicKey,
-                            sk: SecretKey)
-                            -> Result<HandshakeOutcome, HandshakeError>
+/// Like [`server`], but with the stream's read and write sides given
+/// separately.
+pub async fn server_split<R, W>(reader: R,
+                                writer: W,
+                                net_key: NetworkKey,
+                                pk: PublicKey,
+                                sk: SecretKey)
+                                -> Result<HandshakeOutcome, HandshakeError>
+where R: AsyncRead + Unpin,
+      W: AsyncWrite + Unpin,
+{
+    server(Duplex { r: reader, w: writer }, net_key, pk, sk).await
+}
+
+/// Like [`server`], but with `prefix` logically prepended to the stream's
+/// read side, for when the start of the client hello has already been
+/// consumed (eg. by a protocol detector). See [`client_with_prefix`].
+pub async fn server_with_prefix<S>(stream: S,
+                                   prefix: &[u8],
+                                   net_key: NetworkKey,
+                                   pk: PublicKey,
+                                   sk: SecretKey)
+                                   -> Result<HandshakeOutcome, HandshakeError>
 where S: AsyncRead + AsyncWrite + Unpin
 {
+    server(Prefixed { prefix, inner: stream }, net_key, pk, sk).await
+}
 
-    let pk = ServerPublicKey(pk);
-    let sk = ServerSecretKey(sk);
+/// Like [`server`], but stops the handshake as soon as `abort` resolves;
+/// see [`client_abortable`].
+pub async fn server_abortable<S, A>(stream: S,
+                                    net_key: NetworkKey,
+                                    pk: PublicKey,
+                                    sk: SecretKey,
+                                    abort: A)
+                                    -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      A: Future<Output = ()>,
+{
+    let hs = ServerHandshake::new(net_key, pk, sk);
+    let abort = abort.map(|()| HandshakeError::Aborted);
+    run_abortable(stream, hs, abort, &mut HandshakeTimings::default()).await
+}
 
-    let (eph_pk, eph_sk) = server::generate_eph_keypair();
+/// Like [`server`], but also reports how long each part of the handshake
+/// took; see [`client_timed`].
+pub async fn server_timed<S>(stream: S,
+                             net_key: NetworkKey,
+                             pk: PublicKey,
+                             sk: SecretKey)
+                             -> Result<(HandshakeOutcome, HandshakeTimings), HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut timings = HandshakeTimings::default();
+    let start = Instant::now();
+    let hs = ServerHandshake::new(net_key, pk, sk);
+    timings.setup = start.elapsed();
 
-    // Receive and verify client hello
-    let client_eph_pk = {
-        let mut buf = [0u8; 64];
-        await!(stream.read_exact(&mut buf))?;
-        let client_hello = ClientHello::from_slice(&buf)?;
-        client_hello.verify(&net_key)?
-    };
+    let outcome = run_abortable(stream, hs, future::pending(), &mut timings).await?;
+    timings.total = start.elapsed();
+    Ok((outcome, timings))
+}
 
-    // Send server hello
-    let hello = ServerHello::new(&eph_pk, &net_key);
-    await!(stream.write_all(hello.as_slice()))?;
-    await!(stream.flush())?;
+/// Like [`server`], but with a caller-supplied ephemeral keypair;
+/// see [`client_with_eph`].
+pub async fn server_with_eph<S>(stream: S,
+                                net_key: NetworkKey,
+                                pk: PublicKey,
+                                sk: SecretKey,
+                                eph_pk: EphPublicKey,
+                                eph_sk: EphSecretKey)
+                                -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    let hs = ServerHandshake::with_eph(net_key, pk, sk, eph_pk, eph_sk);
+    run_handshake(stream, hs).await
+}
 
-    // Derive shared secrets
-    let shared_a = SharedA::server_side(&eph_sk, &client_eph_pk)?;
-    let shared_b = SharedB::server_side(&sk, &client_eph_pk)?;
+/// Like [`server`], but the ephemeral keypair is generated from `rng`;
+/// see [`client_with_rng`].
+///
+/// Only available with the `rand_core` feature.
+#[cfg(feature = "rand_core")]
+pub async fn server_with_rng<S, R>(stream: S,
+                                   net_key: NetworkKey,
+                                   pk: PublicKey,
+                                   sk: SecretKey,
+                                   rng: &mut R)
+                                   -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      R: rand_core::RngCore + rand_core::CryptoRng,
+{
+    let (eph_pk, eph_sk) = keypair::generate_eph_keypair(rng);
+    server_with_eph(stream, net_key, pk, sk, eph_pk, eph_sk).await
+}
 
-    // Receive and verify client auth
-    let (client_sig, client_pk) = {
-        let mut buf = [0u8; 112];
-        await!(stream.read_exact(&mut buf))?;
+/// Like [`server`], but only completes the handshake if `authorize` returns
+/// true for the client's (verified) public key. Otherwise, the handshake fails
+/// with `HandshakeError::Unauthorized` before the server's final message is
+/// sent, so a rejected client can't tell whether the server would have
+/// accepted it.
+pub async fn server_with_auth<S, F>(stream: S,
+                                    net_key: NetworkKey,
+                                    pk: PublicKey,
+                                    sk: SecretKey,
+                                    authorize: F)
+                                    -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      F: FnOnce(&PublicKey) -> bool + Send,
+{
+    let hs = ServerHandshake::with_auth(net_key, pk, sk, authorize);
+    run_handshake(stream, hs).await
+}
+
+/// Like [`server`], but accepts clients on any of the networks in `net_keys`,
+/// eg. the main SSB network and a private one on the same listener. Also
+/// returns the index in `net_keys` of the network the client is on. Fails
+/// with `HandshakeError::UnknownNetwork` if it's on none of them (which is
+/// always the case if `net_keys` is empty).
+pub async fn server_multi<S>(mut stream: S,
+                             net_keys: &[NetworkKey],
+                             pk: PublicKey,
+                             sk: SecretKey)
+                             -> Result<(HandshakeOutcome, usize), HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    if net_keys.is_empty() {
+        stream.close().await.unwrap_or(());
+        return Err(HandshakeError::UnknownNetwork);
+    }
+    let mut hs = ServerHandshake::with_net_keys(net_keys.to_vec(), pk, sk);
+    let outcome = run_handshake(stream, &mut hs).await?;
+    Ok((outcome, hs.network_index().unwrap()))
+}
+
+/// Like [`server`], but with a timeout; see [`client_with_timeout`].
+pub async fn server_with_timeout<S, Z>(stream: S,
+                                       net_key: NetworkKey,
+                                       pk: PublicKey,
+                                       sk: SecretKey,
+                                       timeout: Duration,
+                                       sleep: Z)
+                                       -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      Z: Sleep + Unpin,
+{
+    let hs = ServerHandshake::new(net_key, pk, sk);
+    run_handshake(Timeout::new(stream, timeout, sleep), hs).await
+}
+
+/// Like [`server`], but with the time limits in `config`; see
+/// [`client_with_config`].
+pub async fn server_with_config<S, Z>(stream: S,
+                                      net_key: NetworkKey,
+                                      pk: PublicKey,
+                                      sk: SecretKey,
+                                      config: HandshakeConfig,
+                                      sleep: Z)
+                                      -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      Z: Sleep + Unpin,
+{
+    let hs = ServerHandshake::new(net_key, pk, sk);
+    run_with_config(stream, hs, config, sleep).await
+}
+
+/// Like [`server`], but also returns the server's ephemeral secret key.
+///
+/// **This is dangerous**; see [`client_exporting_ephemeral`].
+///
+/// Only available with the `export_ephemeral_INSECURE` feature.
+#[cfg(feature = "export_ephemeral_INSECURE")]
+pub async fn server_exporting_ephemeral<S>(stream: S,
+                                           net_key: NetworkKey,
+                                           pk: PublicKey,
+                                           sk: SecretKey)
+                                           -> Result<(HandshakeOutcome, ServerEphSecretKey), HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut hs = ServerHandshake::new(net_key, pk, sk);
+    let outcome = run_handshake(stream, &mut hs).await?;
+    Ok((outcome, hs.into_ephemeral_secret()))
+}
+
+/// Like [`client`], but for streams implementing tokio's io traits.
+///
+/// Only available with the `tokio` feature.
+#[cfg(feature = "tokio")]
+pub async fn client_tokio<S>(stream: S,
+                             net_key: NetworkKey,
+                             pk: PublicKey,
+                             sk: SecretKey,
+                             server_pk: PublicKey)
+                             -> Result<HandshakeOutcome, HandshakeError>
+where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin
+{
+    client(TokioCompat(stream), net_key, pk, sk, server_pk).await
+}
+
+/// Like [`server`], but for streams implementing tokio's io traits.
+///
+/// Only available with the `tokio` feature.
+#[cfg(feature = "tokio")]
+pub async fn server_tokio<S>(stream: S,
+                             net_key: NetworkKey,
+                             pk: PublicKey,
+                             sk: SecretKey)
+                             -> Result<HandshakeOutcome, HandshakeError>
+where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin
+{
+    server(TokioCompat(stream), net_key, pk, sk).await
+}
 
-        let client_auth = ClientAuth::from_buffer(buf.to_vec())?;
-        client_auth.open_and_verify(&pk, &net_key, &shared_a, &shared_b)?
+/// Runs the handshake to completion.
+async fn run_handshake<S, H>(stream: S, hs: H)
+                             -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      H: Handshake,
+{
+    run_abortable(stream, hs, future::pending(), &mut HandshakeTimings::default()).await
+}
+
+/// Runs the handshake with the limits in `config`.
+async fn run_with_config<S, H, Z>(stream: S,
+                                  hs: H,
+                                  config: HandshakeConfig,
+                                  mut sleep: Z)
+                                  -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      H: Handshake,
+      Z: Sleep + Unpin,
+{
+    let deadline = match config.deadline {
+        Some(d) => Either::Left(sleep.sleep(d.saturating_duration_since(Instant::now()))),
+        None => Either::Right(future::pending()),
     };
+    let deadline = deadline.map(|()| HandshakeError::DeadlineExceeded);
+
+    let mut timings = HandshakeTimings::default();
+    match config.timeout {
+        Some(t) => run_abortable(Timeout::new(stream, t, sleep), hs, deadline, &mut timings).await,
+        None => run_abortable(stream, hs, deadline, &mut timings).await,
+    }
+}
+
+/// Runs the handshake until it completes, fails, or `abort` resolves (to
+/// the error to fail with). The state machine (and the secrets in it) is
+/// dropped before the stream is closed on failure.
+async fn run_abortable<S, H, A>(mut stream: S,
+                                mut hs: H,
+                                abort: A,
+                                timings: &mut HandshakeTimings)
+                                -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      H: Handshake,
+      A: Future<Output = HandshakeError>,
+{
+    let (r, aborted) = {
+        let drive = drive(&mut stream, &mut hs, timings);
+        futures::pin_mut!(drive, abort);
+        match future::select(drive, abort).await {
+            Either::Left((r, _)) => (r, false),
+            Either::Right((e, _)) => (Err(e), true),
+        }
+    };
+    // `drive` reports its own errors to the state machine
+    let r = match r {
+        Err(e) if aborted => Err(state::fail(&hs, e)),
+        r => r,
+    };
+    drop(hs);
+    if r.is_err() {
+        stream.close().await.unwrap_or(());
+    }
+    r
+}
 
-    // Derive shared secret
-    let shared_c = SharedC::server_side(&eph_sk, &client_pk)?;
+/// Move messages between the stream and the handshake state machine,
+/// until the state machine says it's done.
+///
+/// Each handshake message is written with a single `write_all` of the whole
+/// message, followed by one `flush`; nothing is written in pieces, so there's
+/// at most one segment per protocol step (given a stream that accepts the
+/// whole message at once).
+async fn drive<S, H>(mut stream: S, hs: &mut H, timings: &mut HandshakeTimings)
+                     -> Result<HandshakeOutcome, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin,
+      H: Handshake,
+{
+    let mut buf = [0u8; ClientAuth::size()]; // largest message
+    let mut outcome = None;
+    let (mut sent, mut received) = (0, 0);
+    loop {
+        if let Some(msg) = hs.next_message() {
+            let t = Instant::now();
+            stream.write_all(msg).await.map_err(|e| state::fail(hs, e))?;
+            stream.flush().await.map_err(|e| state::fail(hs, e))?;
+            hs.message_sent();
+            timings.send[sent] = t.elapsed();
+            sent += 1;
+        }
+        if let Some(o) = outcome {
+            return Ok(o);
+        }
 
-    // Send server accept
-    let server_acc = ServerAccept::new(&sk, &client_pk, &net_key, &client_sig,
-                                       &shared_a, &shared_b, &shared_c);
-    await!(stream.write_all(server_acc.as_slice()))?;
-    await!(stream.flush())?;
+        let buf = &mut buf[..hs.expected_len()];
+        let t = Instant::now();
+        stream.read_exact(buf).await
+            .map_err(|e| state::fail(hs, HandshakeError::from_read(e, hs.expected_step())))?;
+        timings.wait[received] = t.elapsed();
 
-    Ok(HandshakeOutcome {
-        read_key: client_to_server_key(&pk, &net_key, &shared_a, &shared_b, &shared_c),
-        read_noncegen: NonceGen::new(&eph_pk.0, &net_key),
+        let t = Instant::now();
+        let step = hs.receive(buf)?;
+        timings.crypto[received] = t.elapsed();
+        received += 1;
 
-        write_key: server_to_client_key(&client_pk, &net_key, &shared_a, &shared_b, &shared_c),
-        write_noncegen: NonceGen::new(&client_eph_pk.0, &net_key),
-    })
+        if let Step::Done(o) = step {
+            outcome = Some(o);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -161,45 +738,14 @@ mod tests {
     use core::task::Context;
     use core::pin::Pin;
     use std::io::{self, ErrorKind};
-    use futures::{join, Poll};
+    use futures::{join, task::Poll};
     use futures::executor::block_on;
+    use futures::future::{ready, Future};
+    use hex::FromHex;
 
     extern crate async_ringbuffer;
-    extern crate pin_utils;
-    use pin_utils::unsafe_pinned;
     use ssb_crypto::{generate_longterm_keypair, NetworkKey, PublicKey};
 
-    struct Duplex<R, W> {
-        r: R,
-        w: W,
-    }
-    impl<R, W> Duplex<R, W> {
-        unsafe_pinned!(r: R);
-        unsafe_pinned!(w: W);
-    }
-    impl<R, W> AsyncRead for Duplex<R, W>
-    where
-        R: AsyncRead + Unpin,
-    {
-        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
-            self.r().poll_read(cx, buf)
-        }
-    }
-    impl<R, W> AsyncWrite for Duplex<R, W>
-    where
-        W: AsyncWrite + Unpin,
-    {
-        fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
-            self.w().poll_write(cx, buf)
-        }
-        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
-            self.w().poll_flush(cx)
-        }
-        fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
-            self.w().poll_close(cx)
-        }
-    }
-
     type DuplexRingbufStream = Duplex<async_ringbuffer::Reader, async_ringbuffer::Writer>;
 
     fn make_streams() -> (DuplexRingbufStream, DuplexRingbufStream) {
@@ -209,23 +755,51 @@ mod tests {
         (Duplex { r: s2c_r, w: c2s_w }, Duplex { r: c2s_r, w: s2c_w })
     }
 
-    #[test]
-    fn basic() {
-        let (mut c_stream, mut s_stream) = make_streams();
+    /// Both ends of a connection, and fresh keys for each side.
+    struct Pair {
+        c_stream: DuplexRingbufStream,
+        s_stream: DuplexRingbufStream,
+        c_pk: PublicKey,
+        c_sk: SecretKey,
+        s_pk: PublicKey,
+        s_sk: SecretKey,
+        net_key: NetworkKey,
+    }
+
+    fn pair() -> Pair {
+        let (c_stream, s_stream) = make_streams();
         let (s_pk, s_sk) = generate_longterm_keypair();
         let (c_pk, c_sk) = generate_longterm_keypair();
+        Pair { c_stream, s_stream, c_pk, c_sk, s_pk, s_sk, net_key: NetworkKey::SSB_MAIN_NET }
+    }
 
-        let net_key = NetworkKey::SSB_MAIN_NET;
-        let client_side = client(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk.clone());
+    /// Run the client and server sides of a test to completion.
+    fn run_both<C, S>(client_side: C, server_side: S) -> (C::Output, S::Output)
+    where C: Future,
+          S: Future,
+    {
+        block_on(async { join!(client_side, server_side) })
+    }
+
+    #[test]
+    fn basic() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, net_key } = pair();
+
+        let client_side = client(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk);
         let server_side = server(&mut s_stream, net_key.clone(), s_pk, s_sk);
 
-        let (c_out, s_out) = block_on(async {
-            join!(client_side, server_side)
-        });
+        let (c_out, s_out) = run_both(client_side, server_side);
 
         let mut c_out = c_out.unwrap();
         let mut s_out = s_out.unwrap();
 
+        assert_eq!(c_out.peer_pk, s_pk);
+        assert_eq!(s_out.peer_pk, c_pk);
+
+        assert_eq!(c_out.client_eph_pk, s_out.client_eph_pk);
+        assert_eq!(c_out.server_eph_pk, s_out.server_eph_pk);
+        assert_ne!(c_out.client_eph_pk, c_out.server_eph_pk);
+
         assert_eq!(c_out.write_key, s_out.read_key);
         assert_eq!(c_out.read_key, s_out.write_key);
 
@@ -236,33 +810,866 @@ mod tests {
                    s_out.write_noncegen.next());
     }
 
-    fn is_eof_err<T>(r: &Result<T, HandshakeError>) -> bool {
+    #[test]
+    fn connect_accept_keypairs() {
+        let (mut c_stream, mut s_stream) = make_streams();
+        let s_kp = Keypair::generate();
+        let c_kp = Keypair::generate();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let client_side = connect(&mut c_stream, net_key.clone(), &c_kp, s_kp.public);
+        let server_side = accept(&mut s_stream, net_key, &s_kp);
+        let (c_out, s_out) = run_both(client_side, server_side);
+
+        let (c_out, s_out) = (c_out.unwrap(), s_out.unwrap());
+        assert_eq!(c_out.peer_pk, s_kp.public);
+        assert_eq!(s_out.peer_pk, c_kp.public);
+        assert_eq!(c_out.write_key, s_out.read_key);
+    }
+
+    #[test]
+    fn timed_handshake() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, net_key } = pair();
+
+        let client_side = client_timed(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk);
+        let server_side = server_timed(&mut s_stream, net_key, s_pk, s_sk);
+        let (c_out, s_out) = run_both(client_side, server_side);
+
+        // An in-memory write or read can finish within one tick of a coarse
+        // clock, so sends and reads may take no measurable time. The crypto
+        // steps (scalar multiplications) always do.
+        let (c_t, s_t) = (c_out.unwrap().1, s_out.unwrap().1);
+        let zero = Duration::from_secs(0);
+        for t in &[c_t, s_t] {
+            let parts = t.send.iter().chain(&t.wait).chain(&t.crypto).sum::<Duration>();
+            assert!(t.total >= t.setup + parts);
+            assert!(t.total > zero);
+            assert!(t.crypto.iter().all(|d| *d > zero));
+        }
+        // The client's waits include the server's crypto.
+        assert!(c_t.wait.iter().all(|d| *d > zero));
+    }
+
+    #[test]
+    fn handshake_after_plaintext() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, net_key } = pair();
+
+        let client_side = async {
+            c_stream.write_all(b"STARTSHS").await.unwrap();
+            c_stream.flush().await.unwrap();
+
+            let mut buf = [0; 2];
+            c_stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"OK");
+
+            client(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk).await
+        };
+
+        let server_side = async {
+            let mut buf = [0; 8];
+            s_stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"STARTSHS");
+
+            s_stream.write_all(b"OK").await.unwrap();
+            s_stream.flush().await.unwrap();
+
+            server(&mut s_stream, net_key.clone(), s_pk, s_sk).await
+        };
+
+        let (c_out, s_out) = run_both(client_side, server_side);
+
+        let c_out = c_out.unwrap();
+        let s_out = s_out.unwrap();
+        assert_eq!(c_out.write_key, s_out.read_key);
+        assert_eq!(c_out.read_key, s_out.write_key);
+    }
+
+    #[test]
+    fn server_with_prefix_bytes() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, net_key } = pair();
+
+        let client_side = client(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk);
+        let server_side = async {
+            // eg. a protocol detector peeking at the client hello
+            let mut prefix = [0; 8];
+            s_stream.read_exact(&mut prefix).await.unwrap();
+            server_with_prefix(&mut s_stream, &prefix, net_key.clone(), s_pk, s_sk).await
+        };
+
+        let (c_out, s_out) = run_both(client_side, server_side);
+        let c_out = c_out.unwrap();
+        let s_out = s_out.unwrap();
+        assert_eq!(c_out.write_key, s_out.read_key);
+        assert_eq!(c_out.read_key, s_out.write_key);
+    }
+
+    #[test]
+    fn handshake_byte_counts() {
+        let c = expected_handshake_bytes(Role::Client);
+        let s = expected_handshake_bytes(Role::Server);
+        assert_eq!(c, HandshakeBytes { sent: 64 + 112, received: 64 + 80 });
+        assert_eq!(c.sent, s.received);
+        assert_eq!(c.received, s.sent);
+        assert_eq!(c.total(), s.total());
+    }
+
+    #[cfg(feature = "export_ephemeral_INSECURE")]
+    #[test]
+    fn exported_ephemeral_keys() {
+        use ssb_crypto::handshake::{derive_shared_secret, EphPublicKey, EphSecretKey};
+
+        fn eph_pk(sk: &EphSecretKey) -> EphPublicKey {
+            // scalar mult with the curve25519 base point
+            let mut base = [0; 32];
+            base[0] = 9;
+            let p = derive_shared_secret(sk, &EphPublicKey(base)).unwrap();
+            EphPublicKey::from_slice(&p[..]).unwrap()
+        }
+
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, net_key } = pair();
+
+        let client_side = client_exporting_ephemeral(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk);
+        let server_side = server_exporting_ephemeral(&mut s_stream, net_key.clone(), s_pk, s_sk);
+
+        let (c_out, s_out) = run_both(client_side, server_side);
+        let (mut c_out, c_eph_sk) = c_out.unwrap();
+        let (mut s_out, s_eph_sk) = s_out.unwrap();
+
+        // Each side's read nonces are derived from its own ephemeral pk.
+        assert_eq!(NonceGen::new(&eph_pk(&c_eph_sk.0), &net_key).next(),
+                   c_out.read_noncegen.next());
+        assert_eq!(NonceGen::new(&eph_pk(&s_eph_sk.0), &net_key).next(),
+                   s_out.read_noncegen.next());
+    }
+
+    #[test]
+    fn handshake_then_boxstream() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, net_key } = pair();
+
+        let client_side = async {
+            let o = client(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk).await.unwrap();
+            let mut b = BoxStream::new(&mut c_stream, o);
+            b.write_all(b"ping").await.unwrap();
+            b.flush().await.unwrap();
+
+            let mut buf = [0; 4];
+            b.read_exact(&mut buf).await.unwrap();
+            buf
+        };
+
+        let server_side = async {
+            let o = server(&mut s_stream, net_key.clone(), s_pk, s_sk).await.unwrap();
+            let mut b = BoxStream::new(&mut s_stream, o);
+
+            let mut buf = [0; 4];
+            b.read_exact(&mut buf).await.unwrap();
+            b.write_all(b"pong").await.unwrap();
+            b.flush().await.unwrap();
+            buf
+        };
+
+        let (c_got, s_got) = run_both(client_side, server_side);
+        assert_eq!(&s_got, b"ping");
+        assert_eq!(&c_got, b"pong");
+    }
+
+    #[test]
+    fn boxed_both_directions() {
+        let (c_stream, s_stream) = make_streams();
+        let s_kp = Keypair::generate();
+        let c_kp = Keypair::generate();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let client_side = async {
+            let mut b = connect_boxed(c_stream, net_key.clone(), &c_kp, s_kp.public)
+                .await.unwrap();
+            assert_eq!(b.peer_pk(), &s_kp.public);
+            b.write_all(b"hello server").await.unwrap();
+            b.flush().await.unwrap();
+
+            let mut buf = [0; 12];
+            b.read_exact(&mut buf).await.unwrap();
+            b.close().await.unwrap();
+            buf
+        };
+
+        let server_side = async {
+            let mut b = accept_boxed(s_stream, net_key.clone(), &s_kp).await.unwrap();
+            assert_eq!(b.peer_pk(), &c_kp.public);
+
+            let mut buf = [0; 12];
+            b.read_exact(&mut buf).await.unwrap();
+            b.write_all(b"hello client").await.unwrap();
+            b.flush().await.unwrap();
+
+            let mut rest = vec![];
+            b.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            buf
+        };
+
+        let (c_got, s_got) = run_both(client_side, server_side);
+        assert_eq!(&s_got, b"hello server");
+        assert_eq!(&c_got, b"hello client");
+    }
+
+    #[test]
+    fn split_halves() {
+        let (mut c2s_w, mut c2s_r) = async_ringbuffer::ring_buffer(1024);
+        let (mut s2c_w, mut s2c_r) = async_ringbuffer::ring_buffer(1024);
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let client_side = client_split(&mut s2c_r, &mut c2s_w,
+                                       net_key.clone(), c_pk, c_sk, s_pk);
+        let server_side = server_split(&mut c2s_r, &mut s2c_w,
+                                       net_key.clone(), s_pk, s_sk);
+
+        let (c_out, s_out) = run_both(client_side, server_side);
+
+        let c_out = c_out.unwrap();
+        let s_out = s_out.unwrap();
+        assert_eq!(c_out.write_key, s_out.read_key);
+        assert_eq!(c_out.read_key, s_out.write_key);
+    }
+
+    // Counts the calls to poll_write and poll_flush
+    struct Counting<S> {
+        inner: S,
+        writes: usize,
+        flushes: usize,
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for Counting<S> {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                     -> Poll<Result<usize, std::io::Error>> {
+            Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for Counting<S> {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                      -> Poll<Result<usize, std::io::Error>> {
+            let this = self.get_mut();
+            this.writes += 1;
+            Pin::new(&mut this.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), std::io::Error>> {
+            let this = self.get_mut();
+            this.flushes += 1;
+            Pin::new(&mut this.inner).poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), std::io::Error>> {
+            Pin::new(&mut self.get_mut().inner).poll_close(cx)
+        }
+    }
+
+    #[test]
+    fn one_write_per_message() {
+        let (c_stream, s_stream) = make_streams();
+        let mut c_stream = Counting { inner: c_stream, writes: 0, flushes: 0 };
+        let mut s_stream = Counting { inner: s_stream, writes: 0, flushes: 0 };
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let client_side = client(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk);
+        let server_side = server(&mut s_stream, net_key, s_pk, s_sk);
+        let (c_out, s_out) = run_both(client_side, server_side);
+        c_out.unwrap();
+        s_out.unwrap();
+
+        // hello + auth, hello + accept
+        assert_eq!((c_stream.writes, c_stream.flushes), (2, 2));
+        assert_eq!((s_stream.writes, s_stream.flushes), (2, 2));
+    }
+
+    // Writes at most one byte per poll_write, and returns Pending (after
+    // waking the task) before every other write and on a pseudo-random
+    // half of the calls to poll_flush.
+    struct Stingy<S> {
+        inner: S,
+        rng: u32,
+        stall_write: bool,
+    }
+
+    impl<S> Stingy<S> {
+        fn new(inner: S, seed: u32) -> Stingy<S> {
+            Stingy { inner, rng: seed, stall_write: true }
+        }
+
+        fn coin(&mut self) -> bool {
+            // xorshift32
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 17;
+            self.rng ^= self.rng << 5;
+            self.rng & 1 == 1
+        }
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for Stingy<S> {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                     -> Poll<Result<usize, std::io::Error>> {
+            Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for Stingy<S> {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                      -> Poll<Result<usize, std::io::Error>> {
+            let this = self.get_mut();
+            this.stall_write = !this.stall_write;
+            if !this.stall_write {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.len().min(1);
+            Pin::new(&mut this.inner).poll_write(cx, &buf[..n])
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), std::io::Error>> {
+            let this = self.get_mut();
+            if this.coin() {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Pin::new(&mut this.inner).poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), std::io::Error>> {
+            Pin::new(&mut self.get_mut().inner).poll_close(cx)
+        }
+    }
+
+    #[test]
+    fn handshake_over_stingy_writers() {
+        for seed in 1..20 {
+            let (c_stream, s_stream) = make_streams();
+            let mut c_stream = Stingy::new(c_stream, seed);
+            let mut s_stream = Stingy::new(s_stream, seed * 7919);
+            let (s_pk, s_sk) = generate_longterm_keypair();
+            let (c_pk, c_sk) = generate_longterm_keypair();
+            let net_key = NetworkKey::SSB_MAIN_NET;
+
+            let client_side = client(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk);
+            let server_side = server(&mut s_stream, net_key, s_pk, s_sk);
+            let (c_out, s_out) = run_both(client_side, server_side);
+
+            let (mut c_out, mut s_out) = (c_out.unwrap(), s_out.unwrap());
+            assert_eq!(c_out.write_key, s_out.read_key);
+            assert_eq!(c_out.read_key, s_out.write_key);
+            assert_eq!(c_out.peer_pk, s_pk);
+            assert_eq!(s_out.peer_pk, c_pk);
+            assert_eq!(c_out.write_noncegen.next(), s_out.read_noncegen.next());
+            assert_eq!(c_out.read_noncegen.next(), s_out.write_noncegen.next());
+        }
+    }
+
+    #[test]
+    fn box_stream_over_stingy_writers() {
+        let (c_stream, s_stream) = make_streams();
+        let mut c_stream = Stingy::new(c_stream, 3);
+        let mut s_stream = Stingy::new(s_stream, 5);
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let client_side = client(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk);
+        let server_side = server(&mut s_stream, net_key, s_pk, s_sk);
+        let (c_out, s_out) = run_both(client_side, server_side);
+        let mut c_box = c_out.unwrap().into_boxstream(c_stream);
+        let mut s_box = s_out.unwrap().into_boxstream(s_stream);
+
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let writer = async {
+            c_box.write_all(&data[..6000]).await.unwrap();
+            c_box.flush().await.unwrap();
+            c_box.rekey().await.unwrap();
+            c_box.write_all(&data[6000..]).await.unwrap();
+            c_box.close().await.unwrap();
+        };
+        let reader = async {
+            let mut got = Vec::new();
+            s_box.read_to_end(&mut got).await.unwrap();
+            got
+        };
+        let ((), got) = run_both(writer, reader);
+        assert!(got == data);
+    }
+
+    // Timer that never goes off
+    struct Never;
+    impl Future for Never {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+            Poll::Pending
+        }
+    }
+
+    fn fixed_keypair(seed: &str, pk: &str) -> (PublicKey, SecretKey) {
+        let pk = PublicKey::from_slice(&Vec::from_hex(pk).unwrap()).unwrap();
+        let mut sk = Vec::from_hex(seed).unwrap();
+        sk.extend_from_slice(&pk[..]);
+        (pk, SecretKey::from_slice(&sk).unwrap())
+    }
+
+    fn fixed_eph_keypair(b: u8) -> (EphPublicKey, EphSecretKey) {
+        let sk = EphSecretKey::from_slice(&[b; 32]).unwrap();
+        (sk.public_key(), sk)
+    }
+
+    fn fixed_handshake() -> (HandshakeOutcome, HandshakeOutcome) {
+        // RFC 8032 ed25519 test keys 1 and 2
+        let (s_pk, s_sk) = fixed_keypair(
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let (c_pk, c_sk) = fixed_keypair(
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c");
+        let (c_eph_pk, c_eph_sk) = fixed_eph_keypair(1);
+        let (s_eph_pk, s_eph_sk) = fixed_eph_keypair(2);
+
+        let (mut c_stream, mut s_stream) = make_streams();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+        let client_side = client_with_eph(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk,
+                                          c_eph_pk, c_eph_sk);
+        let server_side = server_with_eph(&mut s_stream, net_key, s_pk, s_sk,
+                                          s_eph_pk, s_eph_sk);
+
+        let (c_out, s_out) = run_both(client_side, server_side);
+        (c_out.unwrap(), s_out.unwrap())
+    }
+
+    #[test]
+    fn fixed_eph_keys() {
+        let (mut c_out, mut s_out) = fixed_handshake();
+        let (mut c_out2, _) = fixed_handshake();
+
+        assert_eq!(c_out.write_key, c_out2.write_key);
+        assert_eq!(c_out.read_key, c_out2.read_key);
+        assert_eq!(c_out.write_noncegen.next(), c_out2.write_noncegen.next());
+        assert_eq!(c_out.read_noncegen.next(), c_out2.read_noncegen.next());
+
+        assert_eq!(hex::encode(&c_out.write_key[..]),
+                   "57ec562a72d2ad77a31d0489724689e65bec412de2e269d4263c286672405154");
+        assert_eq!(hex::encode(&c_out.read_key[..]),
+                   "2691a0eb33fbbbabb1c14817def6cf0c33f1e798b3d02b6044a14ca1813dc2c7");
+        assert_eq!(hex::encode(&s_out.read_noncegen.next()[..]),
+                   "7235c8b88c49facee7155b817cf8d48de6300c185d2d853e");
+        assert_eq!(hex::encode(&s_out.write_noncegen.next()[..]),
+                   "cd530d2c65d5b8de879e68eef82876594b86a6685255ad31");
+    }
+
+    #[cfg(feature = "rand_core")]
+    fn seeded_handshake(seed: u64) -> (HandshakeOutcome, HandshakeOutcome) {
+        use rand_chacha::ChaCha20Rng;
+        use rand_core::SeedableRng;
+
+        let (mut c_stream, mut s_stream) = make_streams();
+        let s_kp = Keypair::from_seed(&[1; 32]);
+        let c_kp = Keypair::from_seed(&[2; 32]);
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        let mut rng2 = ChaCha20Rng::seed_from_u64(seed + 1000);
+
+        let net_key = NetworkKey::SSB_MAIN_NET;
+        let client_side = client_with_rng(&mut c_stream, net_key.clone(), c_kp.public,
+                                          c_kp.secret, s_kp.public, &mut rng);
+        let server_side = server_with_rng(&mut s_stream, net_key, s_kp.public,
+                                          s_kp.secret, &mut rng2);
+
+        let (c_out, s_out) = run_both(client_side, server_side);
+        (c_out.unwrap(), s_out.unwrap())
+    }
+
+    #[cfg(feature = "rand_core")]
+    #[test]
+    fn seeded_rng() {
+        let (c_out, s_out) = seeded_handshake(7);
+        let (c_out2, _) = seeded_handshake(7);
+        let (c_out3, _) = seeded_handshake(8);
+
+        assert_eq!(c_out.write_key, s_out.read_key);
+        assert_eq!(c_out.client_eph_pk, c_out2.client_eph_pk);
+        assert_eq!(c_out.server_eph_pk, c_out2.server_eph_pk);
+        assert_eq!(c_out.write_key, c_out2.write_key);
+        assert_eq!(c_out.read_key, c_out2.read_key);
+
+        assert_ne!(c_out.client_eph_pk, c_out3.client_eph_pk);
+        assert_ne!(c_out.write_key, c_out3.write_key);
+    }
+
+    mod props {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn net_key() -> impl Strategy<Value = NetworkKey> {
+            prop_oneof![Just([0u8; 32]), any::<[u8; 32]>()]
+                .prop_map(|b| network_key_from_slice(&b).unwrap())
+        }
+
+        fn handshake(c_net_key: NetworkKey,
+                     s_net_key: NetworkKey,
+                     c_kp: &Keypair,
+                     s_kp: &Keypair,
+                     eph_sks: ([u8; 32], [u8; 32]))
+                     -> (Result<HandshakeOutcome, HandshakeError>,
+                         Result<HandshakeOutcome, HandshakeError>) {
+
+            let (mut c_stream, mut s_stream) = make_streams();
+            let eph_keypair = |b: &[u8; 32]| {
+                let sk = EphSecretKey::from_slice(b).unwrap();
+                (sk.public_key(), sk)
+            };
+            let (c_eph_pk, c_eph_sk) = eph_keypair(&eph_sks.0);
+            let (s_eph_pk, s_eph_sk) = eph_keypair(&eph_sks.1);
+
+            let client_side = client_with_eph(&mut c_stream, c_net_key, c_kp.public,
+                                              c_kp.secret.clone(), s_kp.public,
+                                              c_eph_pk, c_eph_sk);
+            let server_side = server_with_eph(&mut s_stream, s_net_key, s_kp.public,
+                                              s_kp.secret.clone(), s_eph_pk, s_eph_sk);
+            run_both(client_side, server_side)
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(64))]
+
+            #[test]
+            fn keys_and_nonces_agree(net_key in net_key(),
+                                     c_seed in any::<[u8; 32]>(),
+                                     s_seed in any::<[u8; 32]>(),
+                                     same_keypair in any::<bool>(),
+                                     eph_sks in any::<([u8; 32], [u8; 32])>()) {
+                let c_kp = Keypair::from_seed(&c_seed);
+                let s_kp = Keypair::from_seed(if same_keypair { &c_seed } else { &s_seed });
+
+                let (c_out, s_out) = handshake(net_key.clone(), net_key, &c_kp, &s_kp, eph_sks);
+                let (mut c_out, mut s_out) = (c_out.unwrap(), s_out.unwrap());
+
+                prop_assert_eq!(&c_out.write_key, &s_out.read_key);
+                prop_assert_eq!(&c_out.read_key, &s_out.write_key);
+                prop_assert_eq!(c_out.peer_pk, s_kp.public);
+                prop_assert_eq!(s_out.peer_pk, c_kp.public);
+                prop_assert_eq!(c_out.client_eph_pk, s_out.client_eph_pk);
+                prop_assert_eq!(c_out.server_eph_pk, s_out.server_eph_pk);
+                for _ in 0..3 {
+                    prop_assert_eq!(c_out.write_noncegen.next(), s_out.read_noncegen.next());
+                    prop_assert_eq!(c_out.read_noncegen.next(), s_out.write_noncegen.next());
+                }
+            }
+
+            #[test]
+            fn mismatched_net_keys_fail(c_net_key in net_key(),
+                                        s_net_key in net_key(),
+                                        c_seed in any::<[u8; 32]>(),
+                                        s_seed in any::<[u8; 32]>(),
+                                        eph_sks in any::<([u8; 32], [u8; 32])>()) {
+                prop_assume!(c_net_key != s_net_key);
+                let c_kp = Keypair::from_seed(&c_seed);
+                let s_kp = Keypair::from_seed(&s_seed);
+
+                let (c_out, s_out) = handshake(c_net_key, s_net_key, &c_kp, &s_kp, eph_sks);
+                prop_assert!(is_peer_closed(&c_out, HandshakeStep::ServerHello));
+                prop_assert!(matches!(s_out, Err(HandshakeError::ClientHelloVerifyFailed)));
+            }
+        }
+    }
+
+    #[test]
+    fn timeout_not_reached() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, net_key } = pair();
+        let t = Duration::from_secs(5);
+
+        let client_side = client_with_timeout(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk,
+                                              t, |_| Never);
+        let server_side = server_with_timeout(&mut s_stream, net_key.clone(), s_pk, s_sk,
+                                              t, |_| Never);
+
+        let (c_out, s_out) = run_both(client_side, server_side);
+        assert_eq!(c_out.unwrap().write_key, s_out.unwrap().read_key);
+    }
+
+    #[test]
+    fn timeout_unresponsive_server() {
+        let (mut c_stream, s_stream) = make_streams();
+        let (s_pk, _) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+
+        // Timer goes off as soon as any operation has to wait;
+        // ie. when the client waits for the server hello.
+        let r = block_on(client_with_timeout(&mut c_stream, NetworkKey::SSB_MAIN_NET,
+                                             c_pk, c_sk, s_pk,
+                                             Duration::from_secs(5), |_| ready(())));
         match r {
-            Err(HandshakeError::Io(e)) => e.kind() == ErrorKind::UnexpectedEof,
-            _ => false,
+            Err(HandshakeError::TimedOut) => {},
+            _ => panic!(),
+        };
+        // The client's hello arrived, and then its write side was closed.
+        assert!(s_stream.r.is_closed());
+    }
+
+    // Real timer, without depending on a runtime.
+    fn thread_sleep(d: Duration) -> impl Future<Output = ()> {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        std::thread::spawn(move || {
+            std::thread::sleep(d);
+            let _ = tx.send(());
+        });
+        rx.map(|_| ())
+    }
+
+    // Yields a byte every `interval`.
+    struct Dribble {
+        interval: Duration,
+        timer: Option<Pin<Box<dyn Future<Output = ()>>>>,
+    }
+
+    impl AsyncRead for Dribble {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                     -> Poll<Result<usize, io::Error>> {
+            let interval = self.interval;
+            let timer = self.timer.get_or_insert_with(|| Box::pin(thread_sleep(interval)));
+            match timer.as_mut().poll(cx) {
+                Poll::Ready(()) => {
+                    self.timer = None;
+                    buf[0] = 0;
+                    Poll::Ready(Ok(1))
+                },
+                Poll::Pending => Poll::Pending,
+            }
         }
     }
 
     #[test]
-    fn server_rejects_wrong_netkey() {
+    fn deadline_exceeded_by_slow_peer() {
+        let mut stream = Duplex {
+            r: Dribble { interval: Duration::from_millis(20), timer: None },
+            w: futures::io::sink(),
+        };
+        let (s_pk, s_sk) = generate_longterm_keypair();
+
+        // Each byte arrives well within the timeout, but the client hello
+        // would take 64 * 20ms to arrive.
+        let start = Instant::now();
+        let config = HandshakeConfig {
+            timeout: Some(Duration::from_millis(100)),
+            deadline: Some(start + Duration::from_millis(200)),
+        };
+        let r = block_on(server_with_config(&mut stream, NetworkKey::SSB_MAIN_NET, s_pk, s_sk,
+                                            config, thread_sleep));
+        match r {
+            Err(HandshakeError::DeadlineExceeded) => {},
+            r => panic!("{:?}", r.err()),
+        }
+        assert!(start.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn deadline_not_reached() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, net_key } = pair();
+        let config = HandshakeConfig {
+            timeout: Some(Duration::from_secs(5)),
+            deadline: Some(Instant::now() + Duration::from_secs(10)),
+        };
+
+        let client_side = client_with_config(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk,
+                                             config, |_| Never);
+        let server_side = server_with_config(&mut s_stream, net_key, s_pk, s_sk,
+                                             HandshakeConfig::default(), |_| Never);
+        let (c_out, s_out) = run_both(client_side, server_side);
+        assert_eq!(c_out.unwrap().write_key, s_out.unwrap().read_key);
+    }
+
+    #[test]
+    fn abort_before_client_hello() {
         let (mut c_stream, mut s_stream) = make_streams();
         let (s_pk, s_sk) = generate_longterm_keypair();
-        let (c_pk, c_sk) = generate_longterm_keypair();
 
-        let client_side = client(&mut c_stream, NetworkKey::random(), c_pk, c_sk, s_pk.clone());
+        let r = block_on(server_abortable(&mut s_stream, NetworkKey::SSB_MAIN_NET,
+                                          s_pk, s_sk, ready(())));
+        match r {
+            Err(HandshakeError::Aborted) => {},
+            _ => panic!(),
+        };
+        // The server's write side was closed, without sending anything.
+        let mut buf = [0; 1];
+        assert_eq!(block_on(c_stream.read(&mut buf)).unwrap(), 0);
+    }
+
+    #[test]
+    fn abort_not_triggered() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, net_key } = pair();
+
+        let client_side = client_abortable(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk,
+                                           Never);
+        let server_side = server_abortable(&mut s_stream, net_key, s_pk, s_sk, Never);
+        let (c_out, s_out) = run_both(client_side, server_side);
+        assert_eq!(c_out.unwrap().write_key, s_out.unwrap().read_key);
+    }
+
+    fn is_peer_closed<T>(r: &Result<T, HandshakeError>, step: HandshakeStep) -> bool {
+        match r {
+            Err(HandshakeError::PeerClosedDuringHandshake { step: s }) => *s == step,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn server_rejects_wrong_netkey() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, .. } = pair();
+
+        let client_side = client(&mut c_stream, NetworkKey::random(), c_pk, c_sk, s_pk);
         let server_side = server(&mut s_stream, NetworkKey::random(), s_pk, s_sk);
 
-        let (c_out, s_out) = block_on(async {
-            join!(client_side, server_side)
-        });
+        let (c_out, s_out) = run_both(client_side, server_side);
 
-        assert!(is_eof_err(&c_out));
+        assert!(is_peer_closed(&c_out, HandshakeStep::ServerHello));
         match s_out {
             Err(HandshakeError::ClientHelloVerifyFailed) => {},
             _ => panic!(),
         };
     }
 
+    #[test]
+    fn server_closes_after_client_hello() {
+        let (c2s_w, mut c2s_r) = async_ringbuffer::ring_buffer(1024);
+        let (s2c_w, s2c_r) = async_ringbuffer::ring_buffer(1024);
+        let mut c_stream = Duplex { r: s2c_r, w: c2s_w };
+        let (s_pk, _) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+
+        let client_side = client(&mut c_stream, NetworkKey::SSB_MAIN_NET, c_pk, c_sk, s_pk);
+        let server_side = async move {
+            let mut hello = [0; 64];
+            c2s_r.read_exact(&mut hello).await.unwrap();
+            drop(s2c_w);
+        };
+
+        let (c_out, ()) = run_both(client_side, server_side);
+        assert!(is_peer_closed(&c_out, HandshakeStep::ServerHello));
+        let e = io::Error::from(c_out.unwrap_err());
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_error_stays_io() {
+        struct Reset;
+        impl AsyncRead for Reset {
+            fn poll_read(self: Pin<&mut Self>, _cx: &mut Context, _buf: &mut [u8])
+                         -> Poll<Result<usize, io::Error>> {
+                Poll::Ready(Err(ErrorKind::ConnectionReset.into()))
+            }
+        }
+        let mut stream = Duplex { r: Reset, w: futures::io::sink() };
+        let (s_pk, _) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+
+        match block_on(client(&mut stream, NetworkKey::SSB_MAIN_NET, c_pk, c_sk, s_pk)) {
+            Err(HandshakeError::Io(e)) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+            r => panic!("{:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn server_multi_matches_second_key() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, .. } = pair();
+        let net_keys = [NetworkKey::random(), NetworkKey::random(), NetworkKey::random()];
+
+        let client_side = client(&mut c_stream, net_keys[1].clone(), c_pk, c_sk, s_pk);
+        let server_side = server_multi(&mut s_stream, &net_keys, s_pk, s_sk);
+        let (c_out, s_out) = run_both(client_side, server_side);
+
+        let (s_out, index) = s_out.unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(c_out.unwrap().write_key, s_out.read_key);
+    }
+
+    #[test]
+    fn server_multi_unknown_network() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, .. } = pair();
+        let net_keys = [NetworkKey::random(), NetworkKey::SSB_MAIN_NET];
+
+        let client_side = client(&mut c_stream, NetworkKey::random(), c_pk, c_sk, s_pk);
+        let server_side = server_multi(&mut s_stream, &net_keys, s_pk, s_sk);
+        let (c_out, s_out) = run_both(client_side, server_side);
+
+        assert!(is_peer_closed(&c_out, HandshakeStep::ServerHello));
+        match s_out {
+            Err(HandshakeError::UnknownNetwork) => {},
+            r => panic!("{:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn server_multi_no_keys() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, .. } = pair();
+
+        let client_side = client(&mut c_stream, NetworkKey::SSB_MAIN_NET, c_pk, c_sk, s_pk);
+        let server_side = server_multi(&mut s_stream, &[], s_pk, s_sk);
+        let (c_out, s_out) = run_both(client_side, server_side);
+
+        assert!(is_peer_closed(&c_out, HandshakeStep::ServerHello));
+        match s_out {
+            Err(HandshakeError::UnknownNetwork) => {},
+            r => panic!("{:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn server_auth_accepts() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, net_key } = pair();
+
+        let client_side = client(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk);
+        let server_side = server_with_auth(&mut s_stream, net_key, s_pk, s_sk,
+                                           move |pk| *pk == c_pk);
+
+        let (c_out, s_out) = run_both(client_side, server_side);
+        assert_eq!(c_out.unwrap().peer_pk, s_pk);
+        assert_eq!(s_out.unwrap().peer_pk, c_pk);
+    }
+
+    #[test]
+    fn server_auth_borrows_whitelist() {
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let (other_pk, _) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+        let whitelist = [other_pk, c_pk];
+
+        // The same whitelist, borrowed by two connections
+        for _ in 0..2 {
+            let (mut c_stream, mut s_stream) = make_streams();
+            let client_side = client(&mut c_stream, net_key.clone(), c_pk, c_sk.clone(), s_pk);
+            let server_side = server_with_auth(&mut s_stream, net_key.clone(), s_pk,
+                                               s_sk.clone(), |pk| whitelist.contains(pk));
+
+            let (c_out, s_out) = run_both(client_side, server_side);
+            c_out.unwrap();
+            assert_eq!(s_out.unwrap().peer_pk, c_pk);
+        }
+    }
+
+    #[test]
+    fn server_auth_rejects() {
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, .. } = pair();
+        let (other_pk, _) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let client_side = client(&mut c_stream, net_key.clone(), c_pk, c_sk, s_pk);
+        let server_side = server_with_auth(&mut s_stream, net_key, s_pk, s_sk,
+                                           move |pk| *pk == other_pk);
+
+        let (c_out, s_out) = run_both(client_side, server_side);
+        assert!(is_peer_closed(&c_out, HandshakeStep::ServerAccept));
+        match s_out {
+            Err(HandshakeError::Unauthorized) => {},
+            r => panic!("{:?}", r.err()),
+        }
+    }
+
     #[test]
     fn reject_wrong_server_pk() {
         test_handshake_with_bad_server_pk(
@@ -275,21 +1682,52 @@ mod tests {
     }
 
     fn test_handshake_with_bad_server_pk(bad_pk: PublicKey) {
-        let (mut c_stream, mut s_stream) = make_streams();
-        let (s_pk, s_sk) = generate_longterm_keypair();
-        let (c_pk, c_sk) = generate_longterm_keypair();
+        let Pair { mut c_stream, mut s_stream, c_pk, c_sk, s_pk, s_sk, net_key } = pair();
 
-        let net_key = NetworkKey::SSB_MAIN_NET;
 
         let client_side = client(&mut c_stream, net_key.clone(), c_pk, c_sk, bad_pk);
         let server_side = server(&mut s_stream, net_key.clone(), s_pk, s_sk);
 
-        let (c_out, s_out) = block_on(async {
-            join!(client_side, server_side)
-        });
+        let (c_out, s_out) = run_both(client_side, server_side);
 
         assert!(c_out.is_err());
         assert!(s_out.is_err());
     }
 
+    #[test]
+    fn well_known_network_names() {
+        assert!(well_known_networks().iter()
+                .any(|(_, k)| *k == NetworkKey::SSB_MAIN_NET));
+        assert_eq!(network_name(&NetworkKey::SSB_MAIN_NET), Some("ssb-main"));
+        assert_eq!(network_name(&NetworkKey::random()), None);
+    }
+
+    #[test]
+    fn noncegen_increments_past_u16() {
+        use std::collections::HashSet;
+        use ssb_crypto::secretbox::Nonce;
+
+        let start = [7u8; 24];
+        let mut gen = NonceGen::with_starting_nonce(Nonce(start));
+
+        let mut seen = HashSet::new();
+        let mut prev = u128::from_be_bytes(widen(&gen.next().0));
+        seen.insert(prev);
+
+        for _ in 0..70_000 {
+            let n = u128::from_be_bytes(widen(&gen.next().0));
+            assert_eq!(n, prev + 1);
+            assert!(seen.insert(n));
+            prev = n;
+        }
+        assert!(prev - u128::from_be_bytes(widen(&start)) > 0xffff);
+    }
+
+    fn widen(n: &[u8; 24]) -> [u8; 16] {
+        // The top 8 bytes don't change over the range we test.
+        let mut b = [0; 16];
+        b.copy_from_slice(&n[8..]);
+        b
+    }
+
 }
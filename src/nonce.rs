@@ -0,0 +1,175 @@
+use core::fmt;
+
+use ssb_crypto::{handshake::EphPublicKey, NetworkKey};
+use ssb_crypto::secretbox::Nonce;
+
+/// Generates the sequence of nonces used by a box stream, starting from
+/// a nonce derived during the handshake.
+///
+/// Nonces are incremented as 24-byte big-endian integers. Unlike
+/// `ssb_crypto::NonceGen`, this doesn't wrap around: once the all-ones nonce
+/// has been handed out, the generator is exhausted, and [`try_next`] returns
+/// `None` from then on. Reusing a nonce would break the confidentiality
+/// of the stream.
+///
+/// [`try_next`]: NonceGen::try_next
+pub struct NonceGen {
+    next_nonce: Option<Nonce>,
+}
+
+impl NonceGen {
+    pub fn new(pk: &EphPublicKey, net_key: &NetworkKey) -> NonceGen {
+        NonceGen::with_starting_nonce(ssb_crypto::NonceGen::new(pk, net_key).next())
+    }
+
+    pub fn with_starting_nonce(nonce: Nonce) -> NonceGen {
+        NonceGen { next_nonce: Some(nonce) }
+    }
+
+    /// The next nonce, or `None` if the nonce space has been used up.
+    pub fn try_next(&mut self) -> Option<Nonce> {
+        let n = self.next_nonce?;
+
+        let mut next = n;
+        let mut wrapped = true;
+        for byte in next.0.iter_mut().rev() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                wrapped = false;
+                break;
+            }
+        }
+        self.next_nonce = if wrapped { None } else { Some(next) };
+        Some(n)
+    }
+
+    /// The next nonce.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the nonce space has been used up; see [`try_next`](NonceGen::try_next).
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Nonce {
+        self.try_next().expect("nonce generator exhausted")
+    }
+
+    /// True once every nonce has been handed out.
+    pub fn is_exhausted(&self) -> bool {
+        self.next_nonce.is_none()
+    }
+
+    /// True if at least `n` more nonces can be generated.
+    pub(crate) fn has_remaining(&self, n: usize) -> bool {
+        let mut g = NonceGen { next_nonce: self.next_nonce };
+        (0..n).all(|_| g.try_next().is_some())
+    }
+}
+
+/// Shows the next nonce that will be handed out.
+impl fmt::Debug for NonceGen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.next_nonce {
+            Some(n) => f.debug_struct("NonceGen").field("next_nonce", &Hex(&n.0)).finish(),
+            None => f.write_str("NonceGen(exhausted)"),
+        }
+    }
+}
+
+/// Serialized as the next nonce that will be handed out (or none, if
+/// exhausted), so a deserialized generator continues where this one left off.
+#[cfg(feature = "serde")]
+impl serde::Serialize for NonceGen {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.next_nonce.map(|n| n.0).serialize(s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for NonceGen {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<NonceGen, D::Error> {
+        let n = Option::<[u8; 24]>::deserialize(d)?;
+        Ok(NonceGen { next_nonce: n.map(Nonce) })
+    }
+}
+
+/// Formats bytes as hex, for Debug impls.
+pub(crate) struct Hex<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Debug for Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_ssb_crypto() {
+        let (pk, _) = ssb_crypto::handshake::generate_ephemeral_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let mut ours = NonceGen::new(&pk, &net_key);
+        let mut theirs = ssb_crypto::NonceGen::new(&pk, &net_key);
+        for _ in 0..300 {
+            assert_eq!(ours.next(), theirs.next());
+        }
+    }
+
+    #[test]
+    fn overflow_is_detected() {
+        let mut start = [0xff; 24];
+        start[23] = 0xfe;
+        let mut gen = NonceGen::with_starting_nonce(Nonce(start));
+
+        assert!(gen.has_remaining(2));
+        assert!(!gen.has_remaining(3));
+        assert_eq!(gen.try_next(), Some(Nonce(start)));
+        assert!(!gen.is_exhausted());
+        assert_eq!(gen.try_next(), Some(Nonce([0xff; 24])));
+        assert!(gen.is_exhausted());
+        assert_eq!(gen.try_next(), None);
+        assert_eq!(gen.try_next(), None);
+    }
+
+    #[test]
+    fn debug() {
+        let mut gen = NonceGen::with_starting_nonce(Nonce([0xff; 24]));
+        assert_eq!(format!("{:?}", gen), format!("NonceGen {{ next_nonce: {} }}", "ff".repeat(24)));
+        let _ = gen.next();
+        assert_eq!(format!("{:?}", gen), "NonceGen(exhausted)");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_continues_sequence() {
+        let mut start = [0; 24];
+        start[23] = 0xfe;
+        let mut gen = NonceGen::with_starting_nonce(Nonce(start));
+        let _ = gen.next();
+
+        let json = serde_json::to_string(&gen).unwrap();
+        let mut restored: NonceGen = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.next(), gen.next());
+        assert_eq!(restored.next(), gen.next());
+        assert_eq!(restored.next().0[22], 1);
+
+        let mut gen = NonceGen::with_starting_nonce(Nonce([0xff; 24]));
+        let _ = gen.next();
+        let json = serde_json::to_string(&gen).unwrap();
+        let restored: NonceGen = serde_json::from_str(&json).unwrap();
+        assert!(restored.is_exhausted());
+    }
+
+    #[test]
+    #[should_panic]
+    fn next_panics_when_exhausted() {
+        let mut gen = NonceGen::with_starting_nonce(Nonce([0xff; 24]));
+        let _ = gen.next();
+        let _ = gen.next();
+    }
+}
@@ -0,0 +1,256 @@
+use core::fmt;
+
+use ssb_crypto::{PublicKey, secretbox};
+use ssb_crypto::handshake::EphPublicKey;
+
+use crate::{BoxStream, NonceGen};
+use crate::nonce::Hex;
+
+/// Result of a successful handshake.
+///
+/// The keys and nonce generators are named relative to the local side:
+/// `write_*` is for data we send to the peer, and `read_*` is for data we
+/// receive from it. So the client's `write_key` is the server's `read_key`
+/// (the client-to-server key), and vice versa.
+pub struct HandshakeOutcome {
+    /// Key for decrypting data received from the peer
+    pub read_key: secretbox::Key,
+    /// Nonces for decrypting data received from the peer
+    pub read_noncegen: NonceGen,
+
+    /// Key for encrypting data sent to the peer
+    pub write_key: secretbox::Key,
+    /// Nonces for encrypting data sent to the peer
+    pub write_noncegen: NonceGen,
+
+    /// The peer's long-term public key, as verified by the handshake.
+    /// For the client, this is the server's key that it was given;
+    /// for the server, it's the key the client authenticated with.
+    pub peer_pk: PublicKey,
+
+    /// The client's ephemeral public key, as sent in the client hello
+    pub client_eph_pk: EphPublicKey,
+    /// The server's ephemeral public key, as sent in the server hello
+    pub server_eph_pk: EphPublicKey,
+}
+
+impl HandshakeOutcome {
+    /// Wrap the handshake's stream in an encrypted box stream.
+    pub fn into_boxstream<S>(self, stream: S) -> BoxStream<S> {
+        BoxStream::new(stream, self)
+    }
+
+    /// Split into the read and write sides, eg. to hand them to separate
+    /// reader and writer tasks. The public keys are dropped.
+    pub fn split(self) -> (ReadHalf, WriteHalf) {
+        (ReadHalf { key: self.read_key, noncegen: self.read_noncegen },
+         WriteHalf { key: self.write_key, noncegen: self.write_noncegen })
+    }
+}
+
+/// The key and nonces for decrypting data received from the peer;
+/// see [`HandshakeOutcome::split`].
+pub struct ReadHalf {
+    pub key: secretbox::Key,
+    pub noncegen: NonceGen,
+}
+
+/// The key and nonces for encrypting data sent to the peer;
+/// see [`HandshakeOutcome::split`].
+///
+/// Like the nonce generator it holds, this isn't `Clone`: two copies would
+/// encrypt different data with the same nonces.
+pub struct WriteHalf {
+    pub key: secretbox::Key,
+    pub noncegen: NonceGen,
+}
+
+/// The key is shown as `"<redacted>"`.
+impl fmt::Debug for ReadHalf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReadHalf")
+            .field("key", &"<redacted>")
+            .field("noncegen", &self.noncegen)
+            .finish()
+    }
+}
+
+/// The key is shown as `"<redacted>"`.
+impl fmt::Debug for WriteHalf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WriteHalf")
+            .field("key", &"<redacted>")
+            .field("noncegen", &self.noncegen)
+            .finish()
+    }
+}
+
+/// Keys are shown as `"<redacted>"`.
+impl fmt::Debug for HandshakeOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HandshakeOutcome")
+            .field("read_key", &"<redacted>")
+            .field("read_noncegen", &self.read_noncegen)
+            .field("write_key", &"<redacted>")
+            .field("write_noncegen", &self.write_noncegen)
+            .field("peer_pk", &Hex(&self.peer_pk.0))
+            .field("client_eph_pk", &Hex(&self.client_eph_pk.0))
+            .field("server_eph_pk", &Hex(&self.server_eph_pk.0))
+            .finish()
+    }
+}
+
+/// Serializes the keys, the nonce generators' positions, and the public keys.
+///
+/// The serialized form contains the session keys in the clear; store it
+/// accordingly. Resuming a stream from the same serialized outcome twice
+/// reuses nonces, which breaks the stream's confidentiality.
+#[cfg(feature = "serde")]
+impl serde::Serialize for HandshakeOutcome {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        OutcomeRef {
+            read_key: &self.read_key.0,
+            read_noncegen: &self.read_noncegen,
+            write_key: &self.write_key.0,
+            write_noncegen: &self.write_noncegen,
+            peer_pk: &self.peer_pk.0,
+            client_eph_pk: &self.client_eph_pk.0,
+            server_eph_pk: &self.server_eph_pk.0,
+        }.serialize(s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for HandshakeOutcome {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<HandshakeOutcome, D::Error> {
+        let o = OutcomeData::deserialize(d)?;
+        Ok(HandshakeOutcome {
+            read_key: secretbox::Key(o.read_key),
+            read_noncegen: o.read_noncegen,
+            write_key: secretbox::Key(o.write_key),
+            write_noncegen: o.write_noncegen,
+            peer_pk: PublicKey(o.peer_pk),
+            client_eph_pk: EphPublicKey(o.client_eph_pk),
+            server_eph_pk: EphPublicKey(o.server_eph_pk),
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct OutcomeRef<'a> {
+    read_key: &'a [u8; 32],
+    read_noncegen: &'a NonceGen,
+    write_key: &'a [u8; 32],
+    write_noncegen: &'a NonceGen,
+    peer_pk: &'a [u8; 32],
+    client_eph_pk: &'a [u8; 32],
+    server_eph_pk: &'a [u8; 32],
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct OutcomeData {
+    read_key: [u8; 32],
+    read_noncegen: NonceGen,
+    write_key: [u8; 32],
+    write_noncegen: NonceGen,
+    peer_pk: [u8; 32],
+    client_eph_pk: [u8; 32],
+    server_eph_pk: [u8; 32],
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_crypto::handshake::generate_ephemeral_keypair;
+    use ssb_crypto::secretbox::{gen_key, gen_nonce};
+
+    fn outcome() -> HandshakeOutcome {
+        let (peer_pk, _) = generate_longterm_keypair();
+        let (client_eph_pk, _) = generate_ephemeral_keypair();
+        let (server_eph_pk, _) = generate_ephemeral_keypair();
+        HandshakeOutcome {
+            read_key: gen_key(),
+            read_noncegen: NonceGen::with_starting_nonce(gen_nonce()),
+            write_key: gen_key(),
+            write_noncegen: NonceGen::with_starting_nonce(gen_nonce()),
+            peer_pk,
+            client_eph_pk,
+            server_eph_pk,
+        }
+    }
+
+    #[test]
+    fn debug_redacts_keys() {
+        let o = outcome();
+        let peer_pk = o.peer_pk;
+
+        let s = format!("{:?}", o);
+        assert!(s.contains("read_key: \"<redacted>\""));
+        assert!(s.contains("write_key: \"<redacted>\""));
+        assert!(!s.contains(&format!("{:?}", Hex(&o.read_key.0))));
+        assert!(!s.contains(&format!("{:?}", Hex(&o.write_key.0))));
+        assert!(s.contains(&format!("{:?}", Hex(&peer_pk.0))));
+    }
+
+    #[tokio::test]
+    async fn split_into_tasks() {
+        let (key_a, key_b) = (gen_key(), gen_key());
+        let (nonce_a, nonce_b) = (gen_nonce(), gen_nonce());
+        let ours = HandshakeOutcome {
+            read_key: key_a.clone(),
+            read_noncegen: NonceGen::with_starting_nonce(nonce_a),
+            write_key: key_b.clone(),
+            write_noncegen: NonceGen::with_starting_nonce(nonce_b),
+            ..outcome()
+        };
+        let theirs = HandshakeOutcome {
+            read_key: key_b,
+            read_noncegen: NonceGen::with_starting_nonce(nonce_b),
+            write_key: key_a,
+            write_noncegen: NonceGen::with_starting_nonce(nonce_a),
+            ..outcome()
+        };
+        let (mut our_read, mut our_write) = ours.split();
+        let (mut their_read, mut their_write) = theirs.split();
+
+        let writer = tokio::spawn(async move {
+            let n = our_write.noncegen.next();
+            secretbox::seal(b"ping", &n, &our_write.key)
+        });
+        let reader = tokio::spawn(async move {
+            let n = our_read.noncegen.next();
+            let c = secretbox::seal(b"pong", &their_write.noncegen.next(), &their_write.key);
+            secretbox::open(&c, &n, &our_read.key)
+        });
+
+        let c = writer.await.unwrap();
+        let n = their_read.noncegen.next();
+        assert_eq!(secretbox::open(&c, &n, &their_read.key).unwrap(), b"ping");
+        assert_eq!(reader.await.unwrap().unwrap(), b"pong");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip() {
+        let mut o = outcome();
+        let _ = o.read_noncegen.next();
+        let _ = o.write_noncegen.next();
+        let _ = o.write_noncegen.next();
+
+        let json = serde_json::to_string(&o).unwrap();
+        let mut r: HandshakeOutcome = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(r.read_key, o.read_key);
+        assert_eq!(r.write_key, o.write_key);
+        assert_eq!(r.peer_pk, o.peer_pk);
+        assert_eq!(r.client_eph_pk, o.client_eph_pk);
+        assert_eq!(r.server_eph_pk, o.server_eph_pk);
+        for _ in 0..3 {
+            assert_eq!(r.read_noncegen.next(), o.read_noncegen.next());
+            assert_eq!(r.write_noncegen.next(), o.write_noncegen.next());
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use core::pin::Pin;
+use std::io;
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::task::{Context, Poll};
+
+/// Stream whose read side yields `prefix` before reading from `inner`.
+pub(crate) struct Prefixed<'a, S> {
+    pub prefix: &'a [u8],
+    pub inner: S,
+}
+
+impl<'a, S> AsyncRead for Prefixed<'a, S>
+where S: AsyncRead + Unpin
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                 -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        if this.prefix.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        let n = buf.len().min(this.prefix.len());
+        buf[..n].copy_from_slice(&this.prefix[..n]);
+        this.prefix = &this.prefix[n..];
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<'a, S> AsyncWrite for Prefixed<'a, S>
+where S: AsyncWrite + Unpin
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                  -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
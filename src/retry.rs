@@ -0,0 +1,155 @@
+use core::future::Future;
+use core::time::Duration;
+use std::io;
+
+use futures::io::{AsyncRead, AsyncWrite};
+use ssb_crypto::{NetworkKey, PublicKey, SecretKey};
+
+use crate::{client, HandshakeError, HandshakeOutcome, Sleep};
+
+/// How many times [`connect_with_retry`] tries to connect, and how long it
+/// waits in between.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first. Zero is treated as one.
+    pub max_attempts: u32,
+    /// Delay after the first failed attempt. The delay doubles after each
+    /// subsequent failure.
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, base_delay: Duration) -> RetryPolicy {
+        RetryPolicy { max_attempts, base_delay }
+    }
+
+    /// Delay after the given (1-based) failed attempt.
+    fn delay(&self, attempt: u32) -> Duration {
+        2u32.checked_pow(attempt - 1)
+            .and_then(|m| self.base_delay.checked_mul(m))
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+/// Connect and perform the client side of the handshake, retrying with
+/// exponential backoff if either fails.
+///
+/// `connect` is called to open a fresh stream for each attempt (eg. a TCP
+/// connect future). `sleep` is used to wait between attempts; see
+/// [`client_with_timeout`](crate::client_with_timeout). Any failure is retried;
+/// if every attempt fails, the last error is returned.
+pub async fn connect_with_retry<C, F, S, Z>(mut connect: C,
+                                            net_key: NetworkKey,
+                                            pk: PublicKey,
+                                            sk: SecretKey,
+                                            server_pk: PublicKey,
+                                            policy: RetryPolicy,
+                                            mut sleep: Z)
+                                            -> Result<(HandshakeOutcome, S), HandshakeError>
+where C: FnMut() -> F,
+      F: Future<Output = Result<S, io::Error>>,
+      S: AsyncRead + AsyncWrite + Unpin,
+      Z: Sleep,
+{
+    let mut attempt = 1;
+    loop {
+        let r = match connect().await {
+            Ok(mut stream) => {
+                client(&mut stream, net_key.clone(), pk, sk.clone(), server_pk).await
+                    .map(|o| (o, stream))
+            },
+            Err(e) => Err(e.into()),
+        };
+
+        match r {
+            Err(_) if attempt < policy.max_attempts => {
+                sleep.sleep(policy.delay(attempt)).await;
+                attempt += 1;
+            },
+            r => return r,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::{Cell, RefCell};
+    use futures::executor::block_on;
+    use futures::future::ready;
+    use futures::join;
+    use ssb_crypto::generate_longterm_keypair;
+
+    use crate::duplex::Duplex;
+    use crate::server;
+
+    #[test]
+    fn backoff_delays() {
+        let p = RetryPolicy::new(5, Duration::from_millis(100));
+        assert_eq!(p.delay(1), Duration::from_millis(100));
+        assert_eq!(p.delay(2), Duration::from_millis(200));
+        assert_eq!(p.delay(4), Duration::from_millis(800));
+        assert_eq!(p.delay(200), Duration::MAX);
+    }
+
+    #[test]
+    fn retry_then_succeed() {
+        let (c2s_w, c2s_r) = async_ringbuffer::ring_buffer(1024);
+        let (s2c_w, s2c_r) = async_ringbuffer::ring_buffer(1024);
+        let mut c_stream = Some(Duplex { r: s2c_r, w: c2s_w });
+        let mut s_stream = Duplex { r: c2s_r, w: s2c_w };
+
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        // Refuses the first two connections
+        let attempts = Cell::new(0);
+        let connect = || {
+            attempts.set(attempts.get() + 1);
+            ready(if attempts.get() < 3 {
+                Err(io::ErrorKind::ConnectionRefused.into())
+            } else {
+                Ok(c_stream.take().unwrap())
+            })
+        };
+        let delays = RefCell::new(vec![]);
+        let sleep = |d| {
+            delays.borrow_mut().push(d);
+            ready(())
+        };
+
+        let policy = RetryPolicy::new(5, Duration::from_secs(1));
+        let client_side = connect_with_retry(connect, net_key.clone(), c_pk, c_sk, s_pk,
+                                             policy, sleep);
+        let server_side = server(&mut s_stream, net_key, s_pk, s_sk);
+        let (c_out, s_out) = block_on(async { join!(client_side, server_side) });
+
+        let (c_out, _stream) = c_out.unwrap();
+        assert_eq!(c_out.write_key, s_out.unwrap().read_key);
+        assert_eq!(attempts.get(), 3);
+        assert_eq!(*delays.borrow(), vec![Duration::from_secs(1), Duration::from_secs(2)]);
+    }
+
+    #[test]
+    fn all_attempts_fail() {
+        let (s_pk, _) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+
+        let attempts = Cell::new(0);
+        let connect = || {
+            attempts.set(attempts.get() + 1);
+            ready(Err::<Duplex<async_ringbuffer::Reader, async_ringbuffer::Writer>, _>(
+                io::ErrorKind::ConnectionRefused.into()))
+        };
+
+        let policy = RetryPolicy::new(3, Duration::from_secs(1));
+        let r = block_on(connect_with_retry(connect, NetworkKey::SSB_MAIN_NET, c_pk, c_sk, s_pk,
+                                            policy, |_| ready(())));
+        match r {
+            Err(HandshakeError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+            _ => panic!(),
+        }
+        assert_eq!(attempts.get(), 3);
+    }
+}
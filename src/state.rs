@@ -0,0 +1,847 @@
+//! Sans-IO handshake state machines.
+//!
+//! [`ClientHandshake`] and [`ServerHandshake`] do all of the handshake's
+//! message construction, verification and key derivation, but none of its IO.
+//! The caller is responsible for moving bytes between the state machine and
+//! the peer, which makes it possible to run the handshake over transports that
+//! don't implement the futures io traits.
+//!
+//! The driving loop looks like this:
+//!
+//! ```text
+//! loop {
+//!     if let Some(msg) = hs.next_message() {
+//!         send(msg);
+//!         hs.message_sent();
+//!     }
+//!     if handshake is done {
+//!         break;
+//!     }
+//!     let buf = receive_exactly(hs.expected_len());
+//!     if let Step::Done(outcome) = hs.receive(&buf)? {
+//!         handshake is done
+//!     }
+//! }
+//! ```
+//!
+//! Note that the server has a final message to send *after* it has produced
+//! the outcome.
+//!
+//! # Secrets
+//!
+//! The long-term secret key, the ephemeral secret key and the derived shared
+//! secrets are all sodiumoxide secret types, which zero their memory when
+//! dropped. The state machines drop the shared secrets as soon as the outcome
+//! has been derived or a step has failed, and the rest when they're dropped.
+//! (Copies made inside `shs_core` while hashing the shared secrets into the
+//! session keys aren't covered by this.)
+
+use core::fmt;
+use core::mem;
+
+use ssb_crypto::{NetworkKey, PublicKey, SecretKey};
+use ssb_crypto::handshake::{EphPublicKey, EphSecretKey, generate_ephemeral_keypair};
+use shs_core::{*, messages::*};
+
+use crate::{HandshakeError, HandshakeOutcome, HandshakeStep, NonceGen};
+use crate::nonce::Hex;
+use crate::trace;
+
+/// Result of feeding a received message to a handshake state machine.
+// Only ever moved a handful of times per handshake, so not worth boxing.
+#[allow(clippy::large_enum_variant)]
+pub enum Step {
+    /// More messages need to be exchanged.
+    Continue,
+    /// The handshake is complete. Before using the outcome, send the message
+    /// returned by `next_message`, if there is one.
+    Done(HandshakeOutcome),
+}
+
+/// Common interface of [`ClientHandshake`] and [`ServerHandshake`].
+pub trait Handshake {
+    /// Bytes that need to be sent to the peer, if any.
+    /// Each message is returned only once.
+    fn next_message(&mut self) -> Option<&[u8]>;
+
+    /// Number of bytes that must be passed to the next call to `receive`.
+    /// Zero once the handshake is complete.
+    fn expected_len(&self) -> usize;
+
+    /// The message the next call to `receive` expects, or `None` once the
+    /// handshake is complete.
+    fn expected_step(&self) -> Option<HandshakeStep>;
+
+    /// Process a message received from the peer. `buf` must be exactly
+    /// `expected_len()` bytes long; if it isn't, this fails with
+    /// `HandshakeError::MalformedMessage`.
+    ///
+    /// Once this has returned an error, or `Step::Done`, the state machine
+    /// must not be fed any more messages.
+    fn receive(&mut self, buf: &[u8]) -> Result<Step, HandshakeError>;
+
+    /// Tell the state machine that the message returned by `next_message`
+    /// has been written and flushed. Only used for tracing.
+    fn message_sent(&mut self) {}
+
+    /// Tell the state machine that the handshake failed outside of
+    /// `receive`, eg. with an IO error, a timeout, or the peer closing the
+    /// connection. Only used for tracing.
+    fn failed(&self, _err: &HandshakeError) {}
+}
+
+impl<H: Handshake + ?Sized> Handshake for &mut H {
+    fn next_message(&mut self) -> Option<&[u8]> {
+        (**self).next_message()
+    }
+
+    fn expected_len(&self) -> usize {
+        (**self).expected_len()
+    }
+
+    fn expected_step(&self) -> Option<HandshakeStep> {
+        (**self).expected_step()
+    }
+
+    fn receive(&mut self, buf: &[u8]) -> Result<Step, HandshakeError> {
+        (**self).receive(buf)
+    }
+
+    fn message_sent(&mut self) {
+        (**self).message_sent()
+    }
+
+    fn failed(&self, err: &HandshakeError) {
+        (**self).failed(err)
+    }
+}
+
+/// Report `err` to `hs` (see [`Handshake::failed`]), and return it.
+pub(crate) fn fail<H, E>(hs: &H, err: E) -> HandshakeError
+where H: Handshake + ?Sized,
+      E: Into<HandshakeError>,
+{
+    let err = err.into();
+    hs.failed(&err);
+    err
+}
+
+fn check_len(step: HandshakeStep, expected: usize, buf: &[u8]) -> Result<(), HandshakeError> {
+    if buf.len() == expected {
+        Ok(())
+    } else {
+        Err(HandshakeError::MalformedMessage { step, expected, got: buf.len() })
+    }
+}
+
+/// Outgoing message buffer.
+struct Outbox {
+    step: HandshakeStep,
+    msg: Vec<u8>,
+    pending: bool,
+    // Taken, but not yet reported as sent
+    in_flight: bool,
+}
+
+impl Outbox {
+    fn empty() -> Outbox {
+        Outbox {
+            step: HandshakeStep::ClientHello,
+            msg: Vec::new(),
+            pending: false,
+            in_flight: false,
+        }
+    }
+
+    fn with(step: HandshakeStep, msg: &[u8]) -> Outbox {
+        let mut o = Outbox::empty();
+        o.put(step, msg);
+        o
+    }
+
+    fn put(&mut self, step: HandshakeStep, msg: &[u8]) {
+        debug_assert!(!self.pending);
+        self.step = step;
+        self.msg.clear();
+        self.msg.extend_from_slice(msg);
+        self.pending = true;
+        self.in_flight = false;
+    }
+
+    fn take(&mut self) -> Option<&[u8]> {
+        if self.pending {
+            self.pending = false;
+            self.in_flight = true;
+            Some(&self.msg)
+        } else {
+            None
+        }
+    }
+
+    fn sent(&mut self) {
+        if mem::replace(&mut self.in_flight, false) {
+            trace::sent(self.step, self.msg.len());
+        }
+    }
+
+    /// The message being sent, if it hasn't been reported as sent yet.
+    fn in_flight(&self) -> Option<HandshakeStep> {
+        if self.in_flight { Some(self.step) } else { None }
+    }
+}
+
+enum ClientState {
+    AwaitingServerHello,
+    AwaitingServerAccept {
+        server_eph_pk: ServerEphPublicKey,
+        shared_a: SharedA,
+        shared_b: SharedB,
+        shared_c: SharedC,
+    },
+    Done,
+}
+
+/// Client side of the handshake.
+pub struct ClientHandshake {
+    net_key: NetworkKey,
+    pk: ClientPublicKey,
+    sk: ClientSecretKey,
+    server_pk: ServerPublicKey,
+    eph_pk: ClientEphPublicKey,
+    eph_sk: ClientEphSecretKey,
+    state: ClientState,
+    outbox: Outbox,
+    span: trace::Span,
+}
+
+impl ClientHandshake {
+    pub fn new(net_key: NetworkKey,
+               pk: PublicKey,
+               sk: SecretKey,
+               server_pk: PublicKey)
+               -> ClientHandshake {
+
+        let (eph_pk, eph_sk) = generate_ephemeral_keypair();
+        ClientHandshake::with_eph(net_key, pk, sk, server_pk, eph_pk, eph_sk)
+    }
+
+    /// Like `new`, but with the given ephemeral keypair instead of a freshly
+    /// generated one. Reusing an ephemeral keypair breaks the forward secrecy
+    /// of the session; this is meant for testing.
+    pub fn with_eph(net_key: NetworkKey,
+                    pk: PublicKey,
+                    sk: SecretKey,
+                    server_pk: PublicKey,
+                    eph_pk: EphPublicKey,
+                    eph_sk: EphSecretKey)
+                    -> ClientHandshake {
+
+        let (eph_pk, eph_sk) = (ClientEphPublicKey(eph_pk), ClientEphSecretKey(eph_sk));
+        let hello = ClientHello::new(&eph_pk, &net_key);
+
+        ClientHandshake {
+            outbox: Outbox::with(HandshakeStep::ClientHello, hello.as_slice()),
+            net_key,
+            pk: ClientPublicKey(pk),
+            sk: ClientSecretKey(sk),
+            server_pk: ServerPublicKey(server_pk),
+            eph_pk,
+            eph_sk,
+            state: ClientState::AwaitingServerHello,
+            span: trace::Span::client(),
+        }
+    }
+
+    /// The client's ephemeral secret key. See `client_exporting_ephemeral`
+    /// for why you almost certainly don't want this.
+    #[cfg(feature = "export_ephemeral_INSECURE")]
+    pub fn into_ephemeral_secret(self) -> ClientEphSecretKey {
+        self.eph_sk
+    }
+}
+
+impl Handshake for ClientHandshake {
+    fn next_message(&mut self) -> Option<&[u8]> {
+        let _span = self.span.enter();
+        self.outbox.take()
+    }
+
+    fn expected_len(&self) -> usize {
+        match self.state {
+            ClientState::AwaitingServerHello => ServerHello::size(),
+            ClientState::AwaitingServerAccept { .. } => ServerAccept::size(),
+            ClientState::Done => 0,
+        }
+    }
+
+    fn expected_step(&self) -> Option<HandshakeStep> {
+        match self.state {
+            ClientState::AwaitingServerHello => Some(HandshakeStep::ServerHello),
+            ClientState::AwaitingServerAccept { .. } => Some(HandshakeStep::ServerAccept),
+            ClientState::Done => None,
+        }
+    }
+
+    fn receive(&mut self, buf: &[u8]) -> Result<Step, HandshakeError> {
+        let step = self.expected_step()
+            .expect("ClientHandshake::receive called after handshake ended");
+
+        let _span = self.span.enter();
+        trace::received(step, buf.len());
+        let r = self.process(step, buf);
+        trace::result(step, &r);
+        r
+    }
+
+    fn message_sent(&mut self) {
+        let _span = self.span.enter();
+        self.outbox.sent();
+    }
+
+    fn failed(&self, err: &HandshakeError) {
+        let _span = self.span.enter();
+        trace::failed(self.outbox.in_flight().or(self.expected_step()), err);
+    }
+}
+
+impl ClientHandshake {
+    fn process(&mut self, step: HandshakeStep, buf: &[u8]) -> Result<Step, HandshakeError> {
+        check_len(step, self.expected_len(), buf)?;
+
+        match mem::replace(&mut self.state, ClientState::Done) {
+            ClientState::AwaitingServerHello => {
+                let server_eph_pk = ServerHello::from_slice(buf)?.verify(&self.net_key)?;
+
+                // Derive shared secrets
+                let shared_a = SharedA::client_side(&self.eph_sk, &server_eph_pk)?;
+                let shared_b = SharedB::client_side(&self.eph_sk, &self.server_pk)?;
+                let shared_c = SharedC::client_side(&self.sk, &server_eph_pk)?;
+
+                // Send client auth
+                let client_auth = ClientAuth::new(&self.sk, &self.pk, &self.server_pk,
+                                                  &self.net_key, &shared_a, &shared_b);
+                self.outbox.put(HandshakeStep::ClientAuth, client_auth.as_slice());
+
+                self.state = ClientState::AwaitingServerAccept {
+                    server_eph_pk, shared_a, shared_b, shared_c
+                };
+                Ok(Step::Continue)
+            },
+
+            ClientState::AwaitingServerAccept { server_eph_pk, shared_a, shared_b, shared_c } => {
+                let server_acc = ServerAccept::from_buffer(buf.to_vec())?;
+                server_acc.open_and_verify(&self.sk, &self.pk, &self.server_pk,
+                                           &self.net_key, &shared_a,
+                                           &shared_b, &shared_c)?;
+
+                let (pk, net_key) = (&self.pk, &self.net_key);
+                Ok(Step::Done(HandshakeOutcome {
+                    read_key: server_to_client_key(pk, net_key, &shared_a, &shared_b, &shared_c),
+                    read_noncegen: NonceGen::new(&self.eph_pk.0, net_key),
+
+                    write_key: client_to_server_key(&self.server_pk, net_key, &shared_a, &shared_b, &shared_c),
+                    write_noncegen: NonceGen::new(&server_eph_pk.0, net_key),
+
+                    peer_pk: self.server_pk.0,
+                    client_eph_pk: self.eph_pk.0,
+                    server_eph_pk: server_eph_pk.0,
+                }))
+            },
+
+            ClientState::Done => unreachable!(),
+        }
+    }
+}
+
+/// Shows the handshake's progress and public keys; secrets are left out.
+impl fmt::Debug for ClientHandshake {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self.state {
+            ClientState::AwaitingServerHello => "AwaitingServerHello",
+            ClientState::AwaitingServerAccept { .. } => "AwaitingServerAccept",
+            ClientState::Done => "Done",
+        };
+        f.debug_struct("ClientHandshake")
+            .field("state", &state)
+            .field("pk", &Hex(&self.pk.0[..]))
+            .field("server_pk", &Hex(&self.server_pk.0[..]))
+            .finish()
+    }
+}
+
+enum ServerState {
+    AwaitingClientHello,
+    AwaitingClientAuth {
+        client_eph_pk: ClientEphPublicKey,
+        shared_a: SharedA,
+        shared_b: SharedB,
+    },
+    Done,
+}
+
+/// Decides whether a client (identified by its long-term public key) may
+/// complete the handshake.
+type Authorize<'a> = Box<dyn FnOnce(&PublicKey) -> bool + Send + 'a>;
+
+/// Server side of the handshake.
+///
+/// The lifetime is that of the `authorize` callback given to
+/// [`with_auth`](ServerHandshake::with_auth), which may borrow eg. a
+/// whitelist; it's `'static` otherwise.
+pub struct ServerHandshake<'a> {
+    net_key: NetworkKey,
+    pk: ServerPublicKey,
+    sk: ServerSecretKey,
+    eph_pk: ServerEphPublicKey,
+    eph_sk: ServerEphSecretKey,
+    authorize: Option<Authorize<'a>>,
+    // If not empty, the networks the client may be on; `net_key` is set to
+    // the matching one once the client hello has been verified.
+    candidates: Vec<NetworkKey>,
+    network_index: Option<usize>,
+    state: ServerState,
+    outbox: Outbox,
+    span: trace::Span,
+}
+
+impl<'a> ServerHandshake<'a> {
+    pub fn new(net_key: NetworkKey,
+               pk: PublicKey,
+               sk: SecretKey)
+               -> ServerHandshake<'a> {
+
+        let (eph_pk, eph_sk) = generate_ephemeral_keypair();
+        ServerHandshake::with_eph(net_key, pk, sk, eph_pk, eph_sk)
+    }
+
+    /// Like `new`, but with the given ephemeral keypair; see
+    /// [`ClientHandshake::with_eph`].
+    pub fn with_eph(net_key: NetworkKey,
+                    pk: PublicKey,
+                    sk: SecretKey,
+                    eph_pk: EphPublicKey,
+                    eph_sk: EphSecretKey)
+                    -> ServerHandshake<'a> {
+        ServerHandshake::build(net_key, pk, sk, eph_pk, eph_sk, None)
+    }
+
+    /// Like `new`, but `authorize` is called with the client's public key
+    /// once its identity has been verified. If it returns false, the
+    /// handshake fails with `HandshakeError::Unauthorized`, and the final
+    /// message is never sent to the client.
+    pub fn with_auth<F>(net_key: NetworkKey,
+                        pk: PublicKey,
+                        sk: SecretKey,
+                        authorize: F)
+                        -> ServerHandshake<'a>
+    where F: FnOnce(&PublicKey) -> bool + Send + 'a
+    {
+        let (eph_pk, eph_sk) = generate_ephemeral_keypair();
+        ServerHandshake::build(net_key, pk, sk, eph_pk, eph_sk, Some(Box::new(authorize)))
+    }
+
+    /// Like `new`, but accepts a client on any of the networks in `net_keys`.
+    /// Once the client hello has been received, [`network_index`] says which
+    /// one it's on. If it's on none of them, the handshake fails with
+    /// `HandshakeError::UnknownNetwork`.
+    ///
+    /// [`network_index`]: ServerHandshake::network_index
+    ///
+    /// # Panics
+    ///
+    /// Panics if `net_keys` is empty.
+    pub fn with_net_keys(net_keys: Vec<NetworkKey>,
+                         pk: PublicKey,
+                         sk: SecretKey)
+                         -> ServerHandshake<'a> {
+
+        assert!(!net_keys.is_empty(), "no network keys given");
+        let (eph_pk, eph_sk) = generate_ephemeral_keypair();
+        let mut hs = ServerHandshake::build(net_keys[0].clone(), pk, sk, eph_pk, eph_sk, None);
+        hs.candidates = net_keys;
+        hs
+    }
+
+    /// Index into the keys given to [`with_net_keys`](ServerHandshake::with_net_keys)
+    /// of the client's network, once its hello has been verified.
+    pub fn network_index(&self) -> Option<usize> {
+        self.network_index
+    }
+
+    /// Verify the client hello against each candidate network key.
+    /// All of them are tried, so the time taken doesn't depend on which
+    /// one (if any) matches.
+    fn verify_hello_multi(&mut self, buf: &[u8]) -> Result<ClientEphPublicKey, HandshakeError> {
+        let mut found = None;
+        for (i, key) in self.candidates.iter().enumerate() {
+            let r = ClientHello::from_slice(buf)?.verify(key);
+            if let (Ok(eph_pk), None) = (r, &found) {
+                found = Some((i, eph_pk));
+            }
+        }
+
+        let (i, eph_pk) = found.ok_or(HandshakeError::UnknownNetwork)?;
+        self.net_key = self.candidates[i].clone();
+        self.network_index = Some(i);
+        Ok(eph_pk)
+    }
+
+    fn build(net_key: NetworkKey,
+             pk: PublicKey,
+             sk: SecretKey,
+             eph_pk: EphPublicKey,
+             eph_sk: EphSecretKey,
+             authorize: Option<Authorize<'a>>)
+             -> ServerHandshake<'a> {
+
+        ServerHandshake {
+            net_key,
+            pk: ServerPublicKey(pk),
+            sk: ServerSecretKey(sk),
+            eph_pk: ServerEphPublicKey(eph_pk),
+            eph_sk: ServerEphSecretKey(eph_sk),
+            authorize,
+            candidates: Vec::new(),
+            network_index: None,
+            state: ServerState::AwaitingClientHello,
+            outbox: Outbox::empty(),
+            span: trace::Span::server(),
+        }
+    }
+
+    /// The server's ephemeral secret key. See `client_exporting_ephemeral`
+    /// for why you almost certainly don't want this.
+    #[cfg(feature = "export_ephemeral_INSECURE")]
+    pub fn into_ephemeral_secret(self) -> ServerEphSecretKey {
+        self.eph_sk
+    }
+}
+
+impl Handshake for ServerHandshake<'_> {
+    fn next_message(&mut self) -> Option<&[u8]> {
+        let _span = self.span.enter();
+        self.outbox.take()
+    }
+
+    fn expected_len(&self) -> usize {
+        match self.state {
+            ServerState::AwaitingClientHello => ClientHello::size(),
+            ServerState::AwaitingClientAuth { .. } => ClientAuth::size(),
+            ServerState::Done => 0,
+        }
+    }
+
+    fn expected_step(&self) -> Option<HandshakeStep> {
+        match self.state {
+            ServerState::AwaitingClientHello => Some(HandshakeStep::ClientHello),
+            ServerState::AwaitingClientAuth { .. } => Some(HandshakeStep::ClientAuth),
+            ServerState::Done => None,
+        }
+    }
+
+    fn receive(&mut self, buf: &[u8]) -> Result<Step, HandshakeError> {
+        let step = self.expected_step()
+            .expect("ServerHandshake::receive called after handshake ended");
+
+        let _span = self.span.enter();
+        trace::received(step, buf.len());
+        let r = self.process(step, buf);
+        trace::result(step, &r);
+        r
+    }
+
+    fn message_sent(&mut self) {
+        let _span = self.span.enter();
+        self.outbox.sent();
+    }
+
+    fn failed(&self, err: &HandshakeError) {
+        let _span = self.span.enter();
+        trace::failed(self.outbox.in_flight().or(self.expected_step()), err);
+    }
+}
+
+impl ServerHandshake<'_> {
+    fn process(&mut self, step: HandshakeStep, buf: &[u8]) -> Result<Step, HandshakeError> {
+        check_len(step, self.expected_len(), buf)?;
+
+        match mem::replace(&mut self.state, ServerState::Done) {
+            ServerState::AwaitingClientHello => {
+                // Receive and verify client hello
+                let client_eph_pk = if self.candidates.is_empty() {
+                    ClientHello::from_slice(buf)?.verify(&self.net_key)?
+                } else {
+                    self.verify_hello_multi(buf)?
+                };
+
+                // Send server hello
+                let hello = ServerHello::new(&self.eph_pk, &self.net_key);
+                self.outbox.put(HandshakeStep::ServerHello, hello.as_slice());
+
+                // Derive shared secrets
+                let shared_a = SharedA::server_side(&self.eph_sk, &client_eph_pk)?;
+                let shared_b = SharedB::server_side(&self.sk, &client_eph_pk)?;
+
+                self.state = ServerState::AwaitingClientAuth {
+                    client_eph_pk, shared_a, shared_b
+                };
+                Ok(Step::Continue)
+            },
+
+            ServerState::AwaitingClientAuth { client_eph_pk, shared_a, shared_b } => {
+                // Receive and verify client auth
+                let client_auth = ClientAuth::from_buffer(buf.to_vec())?;
+                let (client_sig, client_pk) =
+                    client_auth.open_and_verify(&self.pk, &self.net_key, &shared_a, &shared_b)?;
+
+                if let Some(authorize) = self.authorize.take() {
+                    if !authorize(&client_pk.0) {
+                        return Err(HandshakeError::Unauthorized);
+                    }
+                }
+
+                // Derive shared secret
+                let shared_c = SharedC::server_side(&self.eph_sk, &client_pk)?;
+
+                // Send server accept
+                let server_acc = ServerAccept::new(&self.sk, &client_pk, &self.net_key, &client_sig,
+                                                   &shared_a, &shared_b, &shared_c);
+                self.outbox.put(HandshakeStep::ServerAccept, server_acc.as_slice());
+
+                let (pk, net_key) = (&self.pk, &self.net_key);
+                Ok(Step::Done(HandshakeOutcome {
+                    read_key: client_to_server_key(pk, net_key, &shared_a, &shared_b, &shared_c),
+                    read_noncegen: NonceGen::new(&self.eph_pk.0, net_key),
+
+                    write_key: server_to_client_key(&client_pk, net_key, &shared_a, &shared_b, &shared_c),
+                    write_noncegen: NonceGen::new(&client_eph_pk.0, net_key),
+
+                    peer_pk: client_pk.0,
+                    client_eph_pk: client_eph_pk.0,
+                    server_eph_pk: self.eph_pk.0,
+                }))
+            },
+
+            ServerState::Done => unreachable!(),
+        }
+    }
+}
+
+/// Shows the handshake's progress and public key; secrets are left out.
+impl fmt::Debug for ServerHandshake<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self.state {
+            ServerState::AwaitingClientHello => "AwaitingClientHello",
+            ServerState::AwaitingClientAuth { .. } => "AwaitingClientAuth",
+            ServerState::Done => "Done",
+        };
+        f.debug_struct("ServerHandshake")
+            .field("state", &state)
+            .field("pk", &Hex(&self.pk.0[..]))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ssb_crypto::generate_longterm_keypair;
+
+    fn pass<A: Handshake, B: Handshake>(from: &mut A, to: &mut B) -> Result<Step, HandshakeError> {
+        let mut buf = [0u8; 112];
+        let msg = from.next_message().unwrap();
+        assert_eq!(msg.len(), to.expected_len());
+
+        let buf = &mut buf[..msg.len()];
+        buf.copy_from_slice(msg);
+        to.receive(buf)
+    }
+
+    fn unwrap_done(s: Step) -> HandshakeOutcome {
+        match s {
+            Step::Done(o) => o,
+            Step::Continue => panic!("handshake not done"),
+        }
+    }
+
+    fn handshakes(c_net_key: NetworkKey, s_net_key: NetworkKey)
+                  -> (ClientHandshake, ServerHandshake<'static>) {
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+
+        (ClientHandshake::new(c_net_key, c_pk, c_sk, s_pk),
+         ServerHandshake::new(s_net_key, s_pk, s_sk))
+    }
+
+    #[test]
+    fn drive_by_hand() {
+        let net_key = NetworkKey::SSB_MAIN_NET;
+        let (mut c, mut s) = handshakes(net_key.clone(), net_key);
+
+        assert_eq!(s.next_message(), None);
+        assert_eq!(c.expected_len(), 64);
+        assert_eq!(s.expected_step(), Some(HandshakeStep::ClientHello));
+        assert_eq!(c.expected_step(), Some(HandshakeStep::ServerHello));
+
+        // client hello
+        assert!(matches!(pass(&mut c, &mut s).unwrap(), Step::Continue));
+        assert_eq!(c.next_message(), None);
+
+        // server hello
+        assert!(matches!(pass(&mut s, &mut c).unwrap(), Step::Continue));
+        assert_eq!(c.expected_len(), 80);
+
+        // client auth
+        let mut s_out = unwrap_done(pass(&mut c, &mut s).unwrap());
+        assert_eq!(s.expected_len(), 0);
+
+        // server accept
+        let mut c_out = unwrap_done(pass(&mut s, &mut c).unwrap());
+        assert_eq!(c.expected_len(), 0);
+        assert_eq!(c.expected_step(), None);
+        assert_eq!(s.expected_step(), None);
+        assert_eq!(c.next_message(), None);
+        assert_eq!(s.next_message(), None);
+
+        assert_eq!(c_out.write_key, s_out.read_key);
+        assert_eq!(c_out.read_key, s_out.write_key);
+        assert_eq!(c_out.write_noncegen.next(), s_out.read_noncegen.next());
+        assert_eq!(c_out.read_noncegen.next(), s_out.write_noncegen.next());
+    }
+
+    #[test]
+    fn wrong_netkey() {
+        let (mut c, mut s) = handshakes(NetworkKey::random(), NetworkKey::random());
+        match pass(&mut c, &mut s) {
+            Err(HandshakeError::ClientHelloVerifyFailed) => {},
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn corrupt_server_accept() {
+        let net_key = NetworkKey::SSB_MAIN_NET;
+        let (mut c, mut s) = handshakes(net_key.clone(), net_key);
+
+        pass(&mut c, &mut s).unwrap();
+        pass(&mut s, &mut c).unwrap();
+        pass(&mut c, &mut s).unwrap();
+
+        let mut buf = [0u8; 80];
+        buf.copy_from_slice(s.next_message().unwrap());
+        buf[10] ^= 1;
+        match c.receive(&buf) {
+            Err(HandshakeError::ServerAcceptRejected) => {},
+            _ => panic!(),
+        }
+    }
+
+    fn assert_malformed(r: Result<Step, HandshakeError>, step: HandshakeStep,
+                        expected: usize, got: usize) {
+        match r {
+            Err(HandshakeError::MalformedMessage { step: s, expected: e, got: g }) =>
+                assert_eq!((s, e, g), (step, expected, got)),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn wrong_length_client_hello() {
+        let net_key = NetworkKey::SSB_MAIN_NET;
+        let (mut c, _) = handshakes(net_key.clone(), net_key.clone());
+        let mut hello = c.next_message().unwrap().to_vec();
+
+        let (_, mut s) = handshakes(net_key.clone(), net_key.clone());
+        assert_malformed(s.receive(&hello[..63]), HandshakeStep::ClientHello, 64, 63);
+
+        hello.push(0);
+        let (_, mut s) = handshakes(net_key.clone(), net_key);
+        assert_malformed(s.receive(&hello), HandshakeStep::ClientHello, 64, 65);
+    }
+
+    #[test]
+    fn wrong_length_client_auth() {
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        for &len in &[111, 113] {
+            let (mut c, mut s) = handshakes(net_key.clone(), net_key.clone());
+            pass(&mut c, &mut s).unwrap();
+            pass(&mut s, &mut c).unwrap();
+
+            let mut auth = c.next_message().unwrap().to_vec();
+            auth.resize(len, 0);
+            assert_malformed(s.receive(&auth), HandshakeStep::ClientAuth, 112, len);
+            assert_eq!(s.next_message(), None);
+        }
+    }
+
+    #[test]
+    fn unauthorized_client() {
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let mut c = ClientHandshake::new(net_key.clone(), c_pk, c_sk, s_pk);
+        let mut s = ServerHandshake::with_auth(net_key, s_pk, s_sk,
+                                               move |pk| { assert_eq!(pk, &c_pk); false });
+
+        pass(&mut c, &mut s).unwrap();
+        pass(&mut s, &mut c).unwrap();
+        match pass(&mut c, &mut s) {
+            Err(HandshakeError::Unauthorized) => {},
+            _ => panic!(),
+        }
+        assert_eq!(s.next_message(), None);
+    }
+
+    #[test]
+    fn debug_omits_secrets() {
+        let net_key = NetworkKey::SSB_MAIN_NET;
+        let (mut c, mut s) = handshakes(net_key.clone(), net_key);
+        pass(&mut c, &mut s).unwrap();
+
+        let c_dbg = format!("{:?}", c);
+        assert!(c_dbg.starts_with("ClientHandshake { state: \"AwaitingServerHello\""));
+        assert!(!c_dbg.contains(&format!("{:?}", Hex(&c.sk.0[..]))));
+        assert!(!c_dbg.contains(&format!("{:?}", Hex(&c.eph_sk.0[..]))));
+
+        let s_dbg = format!("{:?}", s);
+        assert!(s_dbg.starts_with("ServerHandshake { state: \"AwaitingClientAuth\""));
+        assert!(!s_dbg.contains(&format!("{:?}", Hex(&s.sk.0[..]))));
+    }
+
+    // The secrets are zeroed by sodiumoxide's `Drop` impls, which `memzero`
+    // the key bytes. A value can't soundly be inspected after it's dropped,
+    // so check that each type has drop glue, and that `memzero` (the same
+    // call the `Drop` impls make) clears a copy of a real key.
+    fn assert_zeroed_on_drop<T>(_: &T) {
+        assert!(mem::needs_drop::<T>());
+    }
+
+    #[test]
+    fn secrets_zeroed_on_drop() {
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (_, c_eph_sk) = client::generate_eph_keypair();
+        let (s_eph_pk, s_eph_sk) = server::generate_eph_keypair();
+        let s_pk = ServerPublicKey(s_pk);
+
+        let shared_a = SharedA::client_side(&c_eph_sk, &s_eph_pk).unwrap();
+        let shared_b = SharedB::client_side(&c_eph_sk, &s_pk).unwrap();
+        let shared_c = SharedC::server_side(&s_eph_sk, &ClientPublicKey(s_pk.0)).unwrap();
+        let key = client_to_server_key(&s_pk, &NetworkKey::SSB_MAIN_NET,
+                                       &shared_a, &shared_b, &shared_c);
+
+        let mut bytes = s_sk.0;
+        assert!(bytes.iter().any(|b| *b != 0));
+        sodiumoxide::utils::memzero(&mut bytes);
+        assert!(bytes.iter().all(|b| *b == 0));
+
+        assert_zeroed_on_drop(&ServerSecretKey(s_sk));
+        assert_zeroed_on_drop(&c_eph_sk);
+        assert_zeroed_on_drop(&s_eph_sk);
+        assert_zeroed_on_drop(&shared_a);
+        assert_zeroed_on_drop(&shared_b);
+        assert_zeroed_on_drop(&shared_c);
+        assert_zeroed_on_drop(&key);
+    }
+}
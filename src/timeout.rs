@@ -0,0 +1,122 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::fmt;
+use core::time::Duration;
+use std::error;
+use std::io;
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::task::{Context, Poll};
+
+/// Source of timer futures, used to implement handshake timeouts without
+/// depending on a particular async runtime.
+///
+/// This is implemented for any `FnMut(Duration) -> impl Future<Output = ()>`,
+/// so on tokio, for example, you can pass `|d| tokio::time::sleep(d)`.
+pub trait Sleep {
+    type Sleep: Future<Output = ()>;
+
+    /// Returns a future that resolves once `dur` has elapsed.
+    fn sleep(&mut self, dur: Duration) -> Self::Sleep;
+}
+
+impl<F, T> Sleep for F
+where F: FnMut(Duration) -> T,
+      T: Future<Output = ()>,
+{
+    type Sleep = T;
+
+    fn sleep(&mut self, dur: Duration) -> T {
+        self(dur)
+    }
+}
+
+/// Payload of the `io::ErrorKind::TimedOut` errors returned by [`Timeout`],
+/// which tells them apart from timeouts reported by the stream itself.
+#[derive(Debug)]
+pub(crate) struct IdleTimeout;
+
+impl fmt::Display for IdleTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("no progress on the stream before the timeout")
+    }
+}
+
+impl error::Error for IdleTimeout {}
+
+/// Stream wrapper that fails any read, write, flush or close that makes no
+/// progress for `dur`, with an `io::ErrorKind::TimedOut` [`IdleTimeout`].
+pub(crate) struct Timeout<S, Z: Sleep> {
+    inner: S,
+    dur: Duration,
+    sleep: Z,
+    timer: Option<Pin<Box<Z::Sleep>>>,
+}
+
+impl<S, Z: Sleep> Timeout<S, Z> {
+    pub fn new(inner: S, dur: Duration, sleep: Z) -> Timeout<S, Z> {
+        Timeout { inner, dur, sleep, timer: None }
+    }
+
+    /// Called when the inner stream isn't ready.
+    /// Starts the timer if needed, and checks whether it has expired.
+    fn poll_timer<T>(&mut self, cx: &mut Context) -> Poll<Result<T, io::Error>> {
+        let (dur, sleep) = (self.dur, &mut self.sleep);
+        let timer = self.timer.get_or_insert_with(|| Box::pin(sleep.sleep(dur)));
+
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.timer = None;
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, IdleTimeout)))
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn check<T>(&mut self, cx: &mut Context, p: Poll<Result<T, io::Error>>)
+                -> Poll<Result<T, io::Error>> {
+        match p {
+            Poll::Pending => self.poll_timer(cx),
+            p => {
+                self.timer = None;
+                p
+            }
+        }
+    }
+}
+
+impl<S, Z> AsyncRead for Timeout<S, Z>
+where S: AsyncRead + Unpin,
+      Z: Sleep + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                 -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        let p = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.check(cx, p)
+    }
+}
+
+impl<S, Z> AsyncWrite for Timeout<S, Z>
+where S: AsyncWrite + Unpin,
+      Z: Sleep + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                  -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        let p = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.check(cx, p)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        let p = Pin::new(&mut this.inner).poll_flush(cx);
+        this.check(cx, p)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        let p = Pin::new(&mut this.inner).poll_close(cx);
+        this.check(cx, p)
+    }
+}
@@ -0,0 +1,21 @@
+use core::time::Duration;
+
+/// How long each part of a handshake took; see `client_timed`.
+///
+/// Each side sends two messages and receives two, so the per-message times
+/// are indexed by message order: for the client, `wait[0]` is the wait for
+/// the server hello and `wait[1]` for the server accept; for the server,
+/// they're the client hello and client auth.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HandshakeTimings {
+    /// Generating the ephemeral keypair and the first message
+    pub setup: Duration,
+    /// Writing and flushing each of our messages
+    pub send: [Duration; 2],
+    /// Waiting for each of the peer's messages
+    pub wait: [Duration; 2],
+    /// Verifying each of the peer's messages and deriving keys from it
+    pub crypto: [Duration; 2],
+    /// The whole handshake, including setup
+    pub total: Duration,
+}
@@ -0,0 +1,271 @@
+//! Handshake instrumentation. With the `tracing` feature, the state machines
+//! run inside a span (`shs_client` or `shs_server`) and emit debug events at
+//! each step; without it, everything here compiles down to nothing.
+//!
+//! Events never include secret key material.
+
+use crate::{HandshakeError, HandshakeStep};
+use crate::state::Step;
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use super::*;
+    use crate::nonce::Hex;
+
+    pub(crate) struct Span(tracing::Span);
+
+    impl Span {
+        pub fn client() -> Span {
+            Span(tracing::debug_span!("shs_client"))
+        }
+
+        pub fn server() -> Span {
+            Span(tracing::debug_span!("shs_server"))
+        }
+
+        /// Enters the span until the guard is dropped. The guard doesn't
+        /// borrow `self`, so the state machine can still be mutated.
+        pub fn enter(&self) -> tracing::span::EnteredSpan {
+            self.0.clone().entered()
+        }
+    }
+
+    pub(crate) fn sent(step: HandshakeStep, len: usize) {
+        tracing::debug!(len, "sent {}", step);
+    }
+
+    pub(crate) fn received(step: HandshakeStep, len: usize) {
+        tracing::debug!(len, "received {}", step);
+    }
+
+    pub(crate) fn result(step: HandshakeStep, r: &Result<Step, HandshakeError>) {
+        match r {
+            Ok(Step::Continue) => tracing::debug!("verified {}", step),
+            Ok(Step::Done(o)) => {
+                tracing::debug!("verified {}", step);
+                tracing::debug!(peer_pk = ?Hex(&o.peer_pk.0), "handshake complete");
+            },
+            Err(e) => tracing::debug!(error = %e, "{} failed", step),
+        }
+    }
+
+    /// A failure outside of the state machine, while sending or waiting
+    /// for `step`.
+    pub(crate) fn failed(step: Option<HandshakeStep>, e: &HandshakeError) {
+        match step {
+            Some(step) => tracing::debug!(error = %e, "{} failed", step),
+            None => tracing::debug!(error = %e, "handshake failed"),
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use super::*;
+
+    pub(crate) struct Span;
+    pub(crate) struct Entered;
+
+    impl Span {
+        pub fn client() -> Span {
+            Span
+        }
+
+        pub fn server() -> Span {
+            Span
+        }
+
+        #[inline(always)]
+        pub fn enter(&self) -> Entered {
+            Entered
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn sent(_step: HandshakeStep, _len: usize) {}
+
+    #[inline(always)]
+    pub(crate) fn received(_step: HandshakeStep, _len: usize) {}
+
+    #[inline(always)]
+    pub(crate) fn result(_step: HandshakeStep, _r: &Result<Step, HandshakeError>) {}
+
+    #[inline(always)]
+    pub(crate) fn failed(_step: Option<HandshakeStep>, _e: &HandshakeError) {}
+}
+
+pub(crate) use imp::*;
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::fmt::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use ssb_crypto::{generate_longterm_keypair, NetworkKey};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use crate::duplex::Duplex;
+    use crate::state::{ClientHandshake, Handshake, ServerHandshake, Step};
+
+    // Records each event as "<span> <message> <other fields>"
+    #[derive(Clone, Default)]
+    struct Collector {
+        events: Arc<Mutex<Vec<String>>>,
+        spans: Arc<Mutex<Vec<&'static str>>>,
+        current: Arc<Mutex<Vec<u64>>>,
+    }
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "message" {
+                write!(self.0, "{:?}", value).unwrap();
+            } else {
+                write!(self.0, " {}={:?}", field.name(), value).unwrap();
+            }
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &Attributes) -> Id {
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(attrs.metadata().name());
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &Id, _: &Record) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let span = match self.current.lock().unwrap().last() {
+                Some(id) => self.spans.lock().unwrap()[*id as usize - 1],
+                None => "-",
+            };
+            let mut fields = Fields(format!("{} ", span));
+            event.record(&mut fields);
+            self.events.lock().unwrap().push(fields.0);
+        }
+
+        fn enter(&self, id: &Id) {
+            self.current.lock().unwrap().push(id.into_u64());
+        }
+
+        fn exit(&self, _: &Id) {
+            self.current.lock().unwrap().pop();
+        }
+    }
+
+    fn run(c: &mut ClientHandshake, s: &mut ServerHandshake) {
+        let mut to_server = true;
+        loop {
+            let (from, to): (&mut dyn Handshake, &mut dyn Handshake) =
+                if to_server { (c, s) } else { (s, c) };
+            let msg = match from.next_message() {
+                Some(m) => m.to_vec(),
+                None => return,
+            };
+            from.message_sent();
+            match to.receive(&msg) {
+                Err(_) => return,
+                Ok(Step::Done(_)) | Ok(Step::Continue) => {},
+            }
+            to_server = !to_server;
+        }
+    }
+
+    fn events(f: impl FnOnce()) -> Vec<String> {
+        let collector = Collector::default();
+        tracing::subscriber::with_default(collector.clone(), f);
+        let events = collector.events.lock().unwrap().clone();
+        events
+    }
+
+    #[test]
+    fn successful_handshake_events() {
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let net_key = NetworkKey::SSB_MAIN_NET;
+
+        let ev = events(|| {
+            let mut c = ClientHandshake::new(net_key.clone(), c_pk, c_sk, s_pk);
+            let mut s = ServerHandshake::new(net_key, s_pk, s_sk);
+            run(&mut c, &mut s);
+        });
+
+        let peer = |pk: &ssb_crypto::PublicKey| hex::encode(&pk[..]);
+        assert_eq!(ev, vec![
+            "shs_client sent client hello len=64".to_string(),
+            "shs_server received client hello len=64".to_string(),
+            "shs_server verified client hello".to_string(),
+            "shs_server sent server hello len=64".to_string(),
+            "shs_client received server hello len=64".to_string(),
+            "shs_client verified server hello".to_string(),
+            "shs_client sent client auth len=112".to_string(),
+            "shs_server received client auth len=112".to_string(),
+            "shs_server verified client auth".to_string(),
+            format!("shs_server handshake complete peer_pk={}", peer(&c_pk)),
+            "shs_server sent server accept len=80".to_string(),
+            "shs_client received server accept len=80".to_string(),
+            "shs_client verified server accept".to_string(),
+            format!("shs_client handshake complete peer_pk={}", peer(&s_pk)),
+        ]);
+    }
+
+    #[test]
+    fn failed_handshake_events() {
+        let (s_pk, s_sk) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+
+        let ev = events(|| {
+            let mut c = ClientHandshake::new(NetworkKey::random(), c_pk, c_sk, s_pk);
+            let mut s = ServerHandshake::new(NetworkKey::random(), s_pk, s_sk);
+            run(&mut c, &mut s);
+        });
+
+        assert_eq!(ev.len(), 3);
+        assert_eq!(ev[1], "shs_server received client hello len=64");
+        assert!(ev[2].starts_with("shs_server client hello failed error="));
+    }
+
+    #[test]
+    fn io_failure_events() {
+        use futures::executor::block_on;
+        use futures::io;
+
+        let (s_pk, _) = generate_longterm_keypair();
+        let (c_pk, c_sk) = generate_longterm_keypair();
+
+        // The server closes the connection without replying.
+        let ev = events(|| {
+            let stream = Duplex { r: io::empty(), w: io::sink() };
+            let r = block_on(crate::client(stream, NetworkKey::SSB_MAIN_NET, c_pk, c_sk, s_pk));
+            assert!(r.is_err());
+        });
+        assert_eq!(ev, vec![
+            "shs_client sent client hello len=64".to_string(),
+            format!("shs_client server hello failed error={}",
+                    crate::HandshakeError::PeerClosedDuringHandshake {
+                        step: crate::HandshakeStep::ServerHello,
+                    }),
+        ]);
+
+        // Nothing is reported as sent if the write fails.
+        let (c_pk, c_sk) = generate_longterm_keypair();
+        let ev = events(|| {
+            let mut out = [0u8; 10];
+            let stream = Duplex { r: io::empty(), w: io::Cursor::new(&mut out[..]) };
+            let r = block_on(crate::client(stream, NetworkKey::SSB_MAIN_NET, c_pk, c_sk, s_pk));
+            assert!(r.is_err());
+        });
+        assert_eq!(ev.len(), 1);
+        assert!(ev[0].starts_with("shs_client client hello failed error="));
+    }
+}
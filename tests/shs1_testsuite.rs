@@ -0,0 +1,68 @@
+//! Runs the reference implementation's test suite
+//! (https://github.com/AljoschaMeyer/shs1-testsuite) against the
+//! `test_client` and `test_server` examples.
+//!
+//! Set `SHS1_TESTSUITE` to the path of a checkout of the test suite (with its
+//! npm dependencies installed). If it isn't set, or `node` isn't available,
+//! the tests are skipped.
+//!
+//! `cargo test` doesn't build the examples as binaries, so run
+//! `cargo build --examples` first.
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn testsuite_script(name: &str) -> Option<PathBuf> {
+    let dir = match env::var_os("SHS1_TESTSUITE") {
+        Some(d) => d,
+        None => {
+            eprintln!("skipping: SHS1_TESTSUITE not set");
+            return None;
+        },
+    };
+    let script = PathBuf::from(dir).join(name);
+    if !script.exists() {
+        eprintln!("skipping: {} not found", script.display());
+        return None;
+    }
+    if Command::new("node").arg("--version").output().is_err() {
+        eprintln!("skipping: node not found");
+        return None;
+    }
+    Some(script)
+}
+
+/// Path of an example binary built alongside this test.
+fn example(name: &str) -> PathBuf {
+    // target/<profile>/deps/<this test> -> target/<profile>/examples/<name>
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    path.pop();
+    path.push("examples");
+    path.push(name);
+    path.set_extension(env::consts::EXE_EXTENSION);
+    path
+}
+
+fn run(script: &str, example_name: &str) {
+    let script = match testsuite_script(script) {
+        Some(s) => s,
+        None => return,
+    };
+    let exe = example(example_name);
+    assert!(exe.exists(), "{} hasn't been built; run `cargo build --examples`", exe.display());
+
+    let status = Command::new("node").arg(&script).arg(&exe).status().unwrap();
+    assert!(status.success(), "{} failed against {}", script.display(), example_name);
+}
+
+#[test]
+fn reference_client_against_our_server() {
+    run("test-server.js", "test_server");
+}
+
+#[test]
+fn reference_server_against_our_client() {
+    run("test-client.js", "test_client");
+}